@@ -0,0 +1,62 @@
+//! Compare `MultiPQueue` with a `PQueue` behind a `Mutex` when several threads insert and pop.
+//!
+//! Run it with `cargo bench --bench concurrent`.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use epqueue::{MultiPQueue, PQueue, Priority};
+
+
+const OPERATIONS_PER_THREAD: u64 = 200_000;
+const PREFILL: u64 = 100_000;
+
+// Each thread alternates inserts and pops on a prefilled queue, like the workers of a graph search.
+fn run<Q: Sync>(queue: Q, threads: u64, insert: fn(&Q, u64), pop: fn(&Q) -> Option<u64>) -> Duration {
+    for key in 0..PREFILL {
+        insert(&queue, key * 7919 % PREFILL);
+    }
+    let queue = &queue;
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for thread_i in 0..threads {
+            scope.spawn(move || {
+                let mut key = thread_i;
+                for _ in 0..OPERATIONS_PER_THREAD / 2 {
+                    key = key.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407) % PREFILL;
+                    insert(queue, key);
+                    pop(queue);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |threads| threads.get() as u64);
+    let mut threads = 1;
+    while threads <= max_threads {
+        let mutex = run(
+            Mutex::new(PQueue::<u64, ()>::new(Priority::Asc)),
+            threads,
+            |queue, key| { queue.lock().unwrap().insert_k(key); },
+            |queue| queue.lock().unwrap().pop_k(),
+        );
+        let multi = run(
+            MultiPQueue::<u64, ()>::new(Priority::Asc, threads as usize),
+            threads,
+            |queue, key| queue.insert_k(key),
+            |queue| queue.pop_k(),
+        );
+        let operations = (threads * OPERATIONS_PER_THREAD) as f64;
+        println!(
+            "{:>3} threads: Mutex<PQueue> {:>8.2} Mops/s, MultiPQueue {:>8.2} Mops/s",
+            threads,
+            operations / mutex.as_secs_f64() / 1e6,
+            operations / multi.as_secs_f64() / 1e6,
+        );
+        threads *= 2;
+    }
+}
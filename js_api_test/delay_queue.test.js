@@ -0,0 +1,39 @@
+const {DelayQueue} = require('./pkg');
+
+test("Should instantiate delay queue", () => {
+    let queue = new DelayQueue();
+    expect(queue.length).toBe(0);
+    expect(queue.nextDeadline()).toBeUndefined();
+    expect(queue.popReady()).toEqual([]);
+})
+
+test("Should pop the ready values in order", () => {
+    let queue = new DelayQueue();
+    queue.schedule(300, "c");
+    queue.schedule(new Date(100), "a");
+    queue.schedule(200, "b");
+    expect(queue.length).toBe(3);
+    expect(queue.nextDeadline()).toBe(100);
+    expect(queue.popReady(50)).toEqual([]);
+    expect(queue.popReady(new Date(250))).toEqual([[100, "a"], [200, "b"]]);
+    expect(queue.popReady()).toEqual([[300, "c"]]);
+    expect(queue.length).toBe(0);
+})
+
+test("Should use the current time by default", () => {
+    let queue = new DelayQueue();
+    queue.schedule(Date.now() - 1000, "past");
+    queue.schedule(Date.now() + 3600000, "future");
+    expect(queue.popReady()).toEqual([[expect.any(Number), "past"]]);
+    expect(queue.length).toBe(1);
+})
+
+test("Should throw with invalid times", () => {
+    let queue = new DelayQueue();
+    expect(() => { queue.schedule("tomorrow", 1); }).toThrow(TypeError);
+    expect(() => { queue.popReady("now"); }).toThrow(TypeError);
+    expect(() => { queue.schedule(new Date("invalid"), 1); }).toThrow(TypeError);
+    expect(() => { queue.schedule(NaN, 1); }).toThrow(TypeError);
+    expect(() => { queue.popReady(new Date("invalid")); }).toThrow(TypeError);
+    expect(queue.length).toBe(0);
+})
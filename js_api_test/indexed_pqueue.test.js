@@ -0,0 +1,52 @@
+const {IndexedPQueue} = require('./pkg');
+
+test("Should instantiate indexed queue", () => {
+    let queue = new IndexedPQueue("asc");
+    expect(queue.length).toBe(0);
+    expect(() => { new IndexedPQueue("up"); }).toThrow(TypeError);
+})
+
+test("Should set and get keys by ID", () => {
+    let data = {"a": 1};
+    let queue = new IndexedPQueue("asc");
+    expect(queue.set("task-1", 5, data)).toBeUndefined();
+    expect(queue.set("task-2", 3)).toBeUndefined();
+    expect(queue.length).toBe(2);
+    expect(queue.get("task-1")).toEqual([5, data]);
+    expect(queue.get("task-2")).toEqual([3]);
+    expect(queue.get("task-3")).toBeUndefined();
+    expect(queue.set("task-1", 1, "updated")).toEqual([5, data]);
+    expect(queue.length).toBe(2);
+    expect(queue.top()).toEqual(["task-1", 1, "updated"]);
+})
+
+test("Should update the priority of an ID", () => {
+    let queue = new IndexedPQueue("desc");
+    queue.set("a", 1, "first");
+    queue.set("b", 2);
+    queue.updatePriority("a", 10);
+    expect(queue.pop()).toEqual(["a", 10, "first"]);
+    expect(() => { queue.updatePriority("a", 3); }).toThrow(Error);
+    expect(queue.pop()).toEqual(["b", 2]);
+    expect(() => { queue.pop(); }).toThrow(Error);
+})
+
+test("Should delete IDs", () => {
+    let queue = new IndexedPQueue("asc");
+    queue.set("a", 1);
+    queue.set("b", 2);
+    expect(queue.has("a")).toBe(true);
+    expect(queue.delete("a")).toBe(true);
+    expect(queue.delete("a")).toBe(false);
+    expect(queue.has("a")).toBe(false);
+    expect(queue.pop()).toEqual(["b", 2]);
+    queue.set("c", 3);
+    queue.clear();
+    expect(queue.length).toBe(0);
+})
+
+test("Should not allow non-numeric keys", () => {
+    let queue = new IndexedPQueue("asc");
+    expect(() => { queue.set("a", "key"); }).toThrow(TypeError);
+    expect(queue.length).toBe(0);
+})
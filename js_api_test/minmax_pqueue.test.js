@@ -0,0 +1,39 @@
+const {MinMaxPQueue} = require('./pkg');
+
+test("Should instantiate min-max queue", () => {
+    let queue = new MinMaxPQueue();
+    expect(queue.length).toBe(0);
+})
+
+test("Should pop the smallest and the greatest keys", () => {
+    let data = {"a": 1, "b": 2, "c": 3};
+    let queue = new MinMaxPQueue();
+    queue.insertK(5);
+    queue.insertKV(1, data);
+    queue.insertK(9);
+    queue.insertK(3);
+    expect(queue.length).toBe(4);
+    expect(queue.topMin()).toEqual([1, data]);
+    expect(queue.topMax()).toEqual([9]);
+    expect(queue.popMax()).toEqual([9]);
+    expect(queue.popMin()).toEqual([1, data]);
+    expect(queue.popMax()).toEqual([5]);
+    expect(queue.popMin()).toEqual([3]);
+    expect(queue.length).toBe(0);
+})
+
+test("Should not allow non-numeric in min-max queue insertK", () => {
+    let queue = new MinMaxPQueue();
+    expect(() => {
+        queue.insertK();
+    }).toThrow(TypeError)
+    expect(queue.length).toBe(0);
+})
+
+test("Should raise exception on popping from empty min-max queue", () => {
+    let queue = new MinMaxPQueue();
+    expect(() => { queue.popMin(); }).toThrow(Error);
+    expect(() => { queue.popMax(); }).toThrow(Error);
+    expect(() => { queue.topMin(); }).toThrow(Error);
+    expect(() => { queue.topMax(); }).toThrow(Error);
+})
@@ -12,6 +12,23 @@ test("Should fail on instantiate with wrong parameter", () => {
     expect(() => { new PQueue(12)}).toThrow(Error);
 })
 
+test("Should instantiate stable queue", () => {
+    let queue = new PQueue("asc", {stable: true});
+    queue = new PQueue("desc", {stable: false});
+    queue = new PQueue("desc", {});
+})
+
+test("Should fail on instantiate with wrong options", () => {
+    expect(() => { new PQueue("asc", 12)}).toThrow(TypeError);
+    expect(() => { new PQueue("asc", {stable: "yes"})}).toThrow(TypeError);
+})
+
+test("Should pop equal keys in insertion order in stable queue", () => {
+    let queue = new PQueue("asc", {stable: true});
+    queue.bulkInsertKV([1, 0, 1, 0, 1], ["a", "b", "c", "d", "e"]);
+    expect(queue.bulkPopKV(5)).toEqual([[0, "b"], [0, "d"], [1, "a"], [1, "c"], [1, "e"]]);
+})
+
 test("Should insert key and insert value", () => {
     let data1 = {"a": 1, "b": 2, "c": 3};
     let data2 = {"a": 1, "b": 2, "c": 3};
@@ -174,3 +191,202 @@ test("should not allow non-numeric in bulkPopK", () => {
     }).toThrow(TypeError)
     expect(queue.length).toBe(3);
 })
+
+test("Should merge queues", () => {
+    let data = {"a": 1, "b": 2, "c": 3};
+    let queue = new PQueue("asc");
+    queue.bulkInsertK([4, 2, 0]);
+    let other = new PQueue("asc");
+    other.bulkInsertKV([3, 1], [data, data]);
+    queue.merge(other);
+    expect(other.length).toBe(0);
+    expect(queue.length).toBe(5);
+    expect(queue.bulkPopKV(5)).toEqual([[0], [1, data], [2], [3, data], [4]]);
+})
+
+test("Should not merge queues with different orders", () => {
+    let queue = new PQueue("asc");
+    let other = new PQueue("desc");
+    other.insertK(1);
+    expect(() => {
+        queue.merge(other);
+    }).toThrow(Error)
+    expect(other.length).toBe(1);
+    expect(queue.length).toBe(0);
+})
+
+test("Should keep the keys with highest priority in bounded queue", () => {
+    let data = {"a": 1, "b": 2, "c": 3};
+    let queue = new PQueue("desc", {maxLen: 2});
+    expect(queue.insertK(1)).toBeUndefined();
+    expect(queue.insertKV(5, data)).toBeUndefined();
+    expect(queue.insertK(0)).toEqual([0]);
+    expect(queue.insertK(3)).toEqual([1]);
+    expect(queue.length).toBe(2);
+    queue.bulkInsertK([7, 8, 9]);
+    expect(queue.length).toBe(2);
+    expect(queue.bulkPopK(2)).toEqual(new Float64Array([9, 8]));
+})
+
+test("Should fail on instantiate with wrong maxLen", () => {
+    expect(() => { new PQueue("asc", {maxLen: -1})}).toThrow(TypeError);
+    expect(() => { new PQueue("asc", {maxLen: 1.5})}).toThrow(TypeError);
+    expect(() => { new PQueue("asc", {maxLen: "10"})}).toThrow(TypeError);
+})
+
+test("Should return the keys and values without removing them", () => {
+    let data = {"a": 1, "b": 2, "c": 3};
+    let queue = new PQueue("desc");
+    queue.bulkInsertK([1, 4, 2]);
+    queue.insertKV(3, data);
+    let array = queue.toArray();
+    expect(array.length).toBe(4);
+    expect(array[0]).toEqual([4]);
+    expect(array).toContainEqual([3, data]);
+    expect(queue.toSortedArray()).toEqual([[4], [3, data], [2], [1]]);
+    expect(queue.length).toBe(4);
+    expect(new PQueue("asc").toSortedArray()).toEqual([]);
+})
+
+test("Should retain the keys accepted by the callback", () => {
+    let queue = new PQueue("asc");
+    queue.bulkInsertKV([5, 1, 4, 2], ["keep", "drop", "drop", "keep"]);
+    queue.insertK(3);
+    queue.retain((key, value) => value === undefined || value === "keep");
+    expect(queue.length).toBe(3);
+    expect(queue.bulkPopKV(3)).toEqual([[2, "keep"], [3], [5, "keep"]]);
+})
+
+test("Should propagate exceptions thrown by the retain callback", () => {
+    let queue = new PQueue("asc");
+    queue.bulkInsertK([1, 2, 3]);
+    expect(() => {
+        queue.retain(() => { throw new RangeError("callback error"); });
+    }).toThrow(RangeError);
+    expect(queue.length).toBe(3);
+})
+
+test("Should clear the queue", () => {
+    let queue = new PQueue("asc");
+    queue.bulkInsertK([1, 2, 3]);
+    queue.clear();
+    expect(queue.length).toBe(0);
+    expect(() => { queue.popK(); }).toThrow(Error);
+})
+
+test("Should restore a queue from its bytes", () => {
+    let queue = new PQueue("desc", {stable: true, maxLen: 10});
+    queue.bulkInsertKV([3, 1, 3, 2], ["first", {n: 1}, "second", [2]]);
+    queue.insertK(0.5);
+    let bytes = queue.toBytes();
+    expect(bytes).toBeInstanceOf(Uint8Array);
+    let restored = PQueue.fromBytes(bytes);
+    expect(restored.length).toBe(5);
+    expect(restored.toSortedArray()).toEqual([[3, "first"], [3, "second"], [2, [2]], [1, {n: 1}], [0.5]]);
+    for (let key = 0; key < 10; key++) {
+        restored.insertK(-key);
+    }
+    expect(restored.length).toBe(10);
+})
+
+test("Should keep the unique keys in bytes", () => {
+    let queue = new PQueue("asc", {unique: "reject"});
+    queue.insertKV(1, "one");
+    queue.insertK(2);
+    let restored = PQueue.fromBytes(queue.toBytes());
+    expect(restored.has(1)).toBe(true);
+    expect(restored.insertKV(1, "again")).toEqual([1, "again"]);
+    expect(restored.count(1)).toBe(1);
+    expect(restored.length).toBe(2);
+})
+
+test("Should not restore corrupted bytes", () => {
+    let queue = new PQueue("asc");
+    queue.bulkInsertK([1, 2, 3]);
+    let bytes = queue.toBytes();
+    bytes[bytes.length - 6] ^= 0xff;
+    expect(() => { PQueue.fromBytes(bytes); }).toThrow(Error);
+    expect(() => { PQueue.fromBytes(new Uint8Array([1, 2, 3])); }).toThrow(Error);
+})
+
+test("Should not convert values that aren't JSON to bytes", () => {
+    let queue = new PQueue("asc");
+    queue.insertKV(1, () => 1);
+    expect(() => { queue.toBytes(); }).toThrow(Error);
+})
+
+test("Should push and pop at once", () => {
+    let queue = new PQueue("asc");
+    expect(queue.pushPop(5, "five")).toEqual([5, "five"]);
+    queue.bulkInsertK([3, 8, 6]);
+    expect(queue.pushPop(1)).toEqual([1]);
+    expect(queue.pushPop(7, "seven")).toEqual([3]);
+    expect(queue.length).toBe(3);
+    expect(queue.bulkPopKV(3)).toEqual([[6], [7, "seven"], [8]]);
+    expect(() => { queue.pushPop("a"); }).toThrow(TypeError);
+})
+
+test("Should replace the top", () => {
+    let queue = new PQueue("desc");
+    expect(queue.replaceTop(2, "two")).toBeUndefined();
+    queue.insertK(4);
+    expect(queue.replaceTop(9)).toEqual([4]);
+    expect(queue.replaceTop(1)).toEqual([9]);
+    expect(queue.length).toBe(2);
+    expect(queue.bulkPopKV(2)).toEqual([[2, "two"], [1]]);
+})
+
+test("Should look up keys", () => {
+    for (let indexed of [false, true]) {
+        let queue = new PQueue("asc", {indexed});
+        queue.bulkInsertKV([4, 2, 4, 7], ["a", "b", "c", "d"]);
+        queue.insertK(0);
+        expect(queue.has(4)).toBe(true);
+        expect(queue.has(-0)).toBe(true);
+        expect(queue.has(5)).toBe(false);
+        expect(queue.count(4)).toBe(2);
+        expect(queue.deleteKey(2)).toEqual([2, "b"]);
+        expect(queue.deleteKey(2)).toBeUndefined();
+        expect(queue.deleteKey(4)[0]).toBe(4);
+        expect(queue.count(4)).toBe(1);
+        expect(queue.bulkPopK(3)).toEqual(new Float64Array([0, 4, 7]));
+    }
+})
+
+test("Should not accept an indexed option that isn't a boolean", () => {
+    expect(() => { new PQueue("asc", {indexed: "yes"}); }).toThrow(TypeError);
+})
+
+test("Should keep unique keys", () => {
+    let rejecting = new PQueue("asc", {unique: "reject"});
+    expect(rejecting.insertKV(1, "first")).toBeUndefined();
+    expect(rejecting.insertKV(1, "second")).toEqual([1, "second"]);
+    rejecting.bulkInsertK([2, 1, 2]);
+    expect(rejecting.length).toBe(2);
+    expect(rejecting.popKV()).toEqual([1, "first"]);
+
+    let replacing = new PQueue("desc", {unique: "replace"});
+    replacing.insertKV(1, "first");
+    expect(replacing.insertKV(1, "second")).toEqual([1, "first"]);
+    expect(replacing.count(1)).toBe(1);
+    expect(replacing.popKV()).toEqual([1, "second"]);
+
+    let keeping = new PQueue("asc", {unique: "keepBetter"});
+    keeping.insertKV(4, "first");
+    expect(keeping.insertKV(4, "second")).toEqual([4, "second"]);
+    expect(keeping.toArray()).toEqual([[4, "first"]]);
+})
+
+test("Should keep the top when pushing a duplicate key", () => {
+    let queue = new PQueue("asc", {unique: "reject"});
+    queue.insertKV(1, "top");
+    queue.insertKV(5, "five");
+    expect(queue.pushPop(5, "new five")).toEqual([5, "new five"]);
+    expect(queue.replaceTop(5, "other five")).toEqual([5, "other five"]);
+    expect(queue.toSortedArray()).toEqual([[1, "top"], [5, "five"]]);
+})
+
+test("Should not accept an unknown unique option", () => {
+    expect(() => { new PQueue("asc", {unique: true}); }).toThrow(TypeError);
+    expect(() => { new PQueue("asc", {unique: "ignore"}); }).toThrow(TypeError);
+})
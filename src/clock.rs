@@ -0,0 +1,25 @@
+use std::time::Instant;
+
+
+/// The source of the current time of a `DelayQueue`, and of the expiring items of a `PQueue`.
+///
+/// Any closure returning the time is a clock, so tests can control the time of the queue.
+pub trait Clock<T> {
+    fn now(&self) -> T;
+}
+
+/// The clock of the system, measured with `Instant`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock<Instant> for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<T, F: Fn() -> T> Clock<T> for F {
+    fn now(&self) -> T {
+        self()
+    }
+}
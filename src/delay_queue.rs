@@ -0,0 +1,146 @@
+use std::cmp;
+use std::time::Instant;
+
+use crate::clock::{Clock, SystemClock};
+use crate::item::Handle;
+use crate::pqueue::{PQueue, Priority};
+
+
+/// A queue of values scheduled at a deadline, a value is ready when its deadline has passed.
+///
+/// The values with the same deadline are ready in the order they were scheduled.
+pub struct DelayQueue<K: cmp::Ord, V, C: Clock<K> = SystemClock> {
+    queue: PQueue<K, V>,
+    clock: C,
+}
+
+impl<V> DelayQueue<Instant, V> {
+    pub fn new() -> DelayQueue<Instant, V> {
+        DelayQueue::with_clock(SystemClock)
+    }
+}
+
+impl<V> Default for DelayQueue<Instant, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: cmp::Ord, V, C: Clock<K>> DelayQueue<K, V, C> {
+    pub fn with_clock(clock: C) -> DelayQueue<K, V, C> {
+        DelayQueue {
+            queue: PQueue::new(Priority::Asc).stable(),
+            clock,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Schedule the value to be ready at the deadline `at`, the handle can cancel it.
+    pub fn schedule(&mut self, at: K, value: V) -> Handle {
+        self.queue.insert_kv(at, value)
+    }
+
+    /// Remove a scheduled value before it's popped, returning its deadline and the value.
+    pub fn cancel(&mut self, handle: Handle) -> Option<(K, V)> {
+        self.queue.remove(handle).ok().map(Self::expect_value)
+    }
+
+    /// The earliest deadline, which may have already passed.
+    pub fn next_deadline(&self) -> Option<&K> {
+        self.queue.top_k()
+    }
+
+    /// Pop the values whose deadline is `now` or earlier, in the order of their deadlines.
+    pub fn pop_ready(&mut self, now: K) -> Vec<(K, V)> {
+        let mut ready = Vec::new();
+        while self.queue.top_k().is_some_and(|deadline| *deadline <= now) {
+            ready.extend(self.queue.pop_kv().map(Self::expect_value));
+        }
+        ready
+    }
+
+    /// Like `pop_ready`, taking the current time from the clock of the queue.
+    pub fn pop_due(&mut self) -> Vec<(K, V)> {
+        let now = self.clock.now();
+        self.pop_ready(now)
+    }
+
+    // Every scheduled item has a value.
+    fn expect_value((deadline, value): (K, Option<V>)) -> (K, V) {
+        (deadline, value.expect("items of DelayQueue always have a value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    fn pop_ready_values() {
+        let mut queue: DelayQueue<u64, &str, _> = DelayQueue::with_clock(|| 0);
+        assert_eq!(queue.next_deadline(), None);
+        queue.schedule(30, "c");
+        queue.schedule(10, "a");
+        queue.schedule(20, "b");
+        queue.schedule(10, "a2");
+        assert_eq!(queue.next_deadline(), Some(&10));
+        assert_eq!(queue.pop_ready(5), vec![]);
+        assert_eq!(queue.pop_ready(10), vec![(10, "a"), (10, "a2")]);
+        assert_eq!(queue.pop_ready(25), vec![(20, "b")]);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop_ready(100), vec![(30, "c")]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn cancel_scheduled_value() {
+        let mut queue: DelayQueue<u64, String, _> = DelayQueue::with_clock(|| 0);
+        let handle = queue.schedule(5, String::from("cancelled"));
+        queue.schedule(7, String::from("kept"));
+        assert_eq!(queue.cancel(handle), Some((5, String::from("cancelled"))));
+        assert_eq!(queue.cancel(handle), None);
+        assert_eq!(queue.next_deadline(), Some(&7));
+    }
+
+    #[test]
+    fn pop_due_with_manual_clock() {
+        let now = Rc::new(Cell::new(0u64));
+        let clock = {
+            let now = Rc::clone(&now);
+            move || now.get()
+        };
+        let mut queue = DelayQueue::with_clock(clock);
+        queue.schedule(100, 1);
+        queue.schedule(200, 2);
+        assert_eq!(queue.pop_due(), vec![]);
+        now.set(150);
+        assert_eq!(queue.clock().now(), 150);
+        assert_eq!(queue.pop_due(), vec![(100, 1)]);
+        now.set(1000);
+        assert_eq!(queue.pop_due(), vec![(200, 2)]);
+    }
+
+    #[test]
+    fn system_clock() {
+        let mut queue: DelayQueue<Instant, &str> = DelayQueue::new();
+        let now = Instant::now();
+        queue.schedule(now, "now");
+        queue.schedule(now + Duration::from_secs(3600), "later");
+        assert_eq!(queue.pop_due(), vec![(now, "now")]);
+        assert_eq!(queue.len(), 1);
+    }
+}
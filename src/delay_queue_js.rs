@@ -0,0 +1,86 @@
+use js_sys::{Array, Date, TypeError};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::clock::Clock;
+use crate::delay_queue::DelayQueue;
+use crate::pqueue_js::NumberJs;
+
+
+/// The clock of JavaScript, in milliseconds since the epoch like `Date.now()`.
+struct DateClock;
+
+impl Clock<NumberJs> for DateClock {
+    fn now(&self) -> NumberJs {
+        NumberJs::new(Date::now())
+    }
+}
+
+// A valid `Date` or a finite number of milliseconds since the epoch, NaN can't be ordered.
+fn timestamp(time: &JsValue, method: &str) -> Result<NumberJs, TypeError> {
+    let millis = match time.dyn_ref::<Date>() {
+        Some(date) => Some(date.get_time()),
+        None => time.as_f64(),
+    };
+    millis
+        .filter(|millis| millis.is_finite())
+        .map(NumberJs::new)
+        .ok_or_else(|| TypeError::new(&format!("{} expect a valid Date or a finite number", method)))
+}
+
+#[wasm_bindgen( js_name = DelayQueue )]
+pub struct DelayQueueJs {
+    queue: DelayQueue<NumberJs, JsValue, DateClock>,
+}
+
+#[wasm_bindgen( js_class = DelayQueue )]
+impl DelayQueueJs {
+    #[wasm_bindgen(constructor)]
+    /// Construct a new queue of values scheduled at a time.
+    pub fn new() -> DelayQueueJs {
+        DelayQueueJs { queue: DelayQueue::with_clock(DateClock) }
+    }
+
+    #[wasm_bindgen(getter)]
+    /// The number of scheduled values.
+    pub fn length(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Schedule the value to be ready at the time `at`, a `Date` or a timestamp in milliseconds.
+    ///
+    /// Throws a `TypeError` if `at` is an invalid `Date` or isn't a finite number.
+    pub fn schedule(&mut self, at: JsValue, value: JsValue) -> Result<(), TypeError> {
+        let at = timestamp(&at, "schedule")?;
+        self.queue.schedule(at, value);
+        Ok(())
+    }
+
+    #[wasm_bindgen( js_name = nextDeadline )]
+    /// The earliest scheduled time in milliseconds, or `undefined` if the queue is empty.
+    pub fn next_deadline(&self) -> Option<f64> {
+        self.queue.next_deadline().map(|at| at.0)
+    }
+
+    #[wasm_bindgen( js_name = popReady )]
+    /// Pop the values scheduled at `now` or earlier, `now` is a `Date` or a timestamp and defaults to `Date.now()`.
+    ///
+    /// Returns an array of pairs time/value, in the order of their times.
+    pub fn pop_ready(&mut self, now: JsValue) -> Result<Array, TypeError> {
+        let ready = if now.is_undefined() {
+            self.queue.pop_due()
+        } else {
+            self.queue.pop_ready(timestamp(&now, "popReady")?)
+        };
+        Ok(ready
+            .into_iter()
+            .map(|(at, value)| JsValue::from(Array::of2(&JsValue::from_f64(at.0), &value)))
+            .collect())
+    }
+}
+
+impl Default for DelayQueueJs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
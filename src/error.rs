@@ -0,0 +1,27 @@
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PQueueError {
+    /// The handle doesn't belong to an item of the queue, it was already popped or removed.
+    InvalidHandle,
+    /// The queues don't share the same priority or comparator.
+    PriorityMismatch,
+    /// The queue only allows unique keys and another item has the key.
+    DuplicateKey,
+    /// No item of the `IndexedPQueue` has the ID.
+    UnknownId,
+}
+
+impl fmt::Display for PQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PQueueError::InvalidHandle => write!(f, "the handle doesn't refer to an item in the queue"),
+            PQueueError::PriorityMismatch => write!(f, "the queues don't have the same priority"),
+            PQueueError::DuplicateKey => write!(f, "another item of the queue has the key"),
+            PQueueError::UnknownId => write!(f, "no item of the queue has the ID"),
+        }
+    }
+}
+
+impl error::Error for PQueueError {}
@@ -0,0 +1,200 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+use crate::error::PQueueError;
+use crate::item::Handle;
+use crate::pqueue::{PQueue, Priority};
+
+
+// The map only has the handles of the items in the queue.
+const VALID_HANDLES: &str = "the handles of the IDs should be valid";
+
+/// A priority queue whose items are identified by an ID, independent from their key.
+///
+/// Each ID is at most once in the queue, so `upsert_kv` either inserts an item or updates the key and the value
+/// of the item with the ID. It uses the heap of `PQueue`, and a map from the IDs to the handles of the items.
+pub struct IndexedPQueue<I: Hash + Eq + Clone, K: cmp::Ord, V> {
+    queue: PQueue<K, (I, Option<V>)>,
+    handles: HashMap<I, Handle>,
+}
+
+impl<I: Hash + Eq + Clone, K: cmp::Ord, V> IndexedPQueue<I, K, V> {
+    pub fn new(priority: Priority) -> IndexedPQueue<I, K, V> {
+        IndexedPQueue {
+            queue: PQueue::new(priority),
+            handles: HashMap::new(),
+        }
+    }
+
+    /// Construct a queue ordered by `compare`, see `PQueue::with_comparator`.
+    pub fn with_comparator<F>(compare: F) -> IndexedPQueue<I, K, V>
+    where
+        F: Fn(&K, &K) -> cmp::Ordering + Send + Sync + 'static
+    {
+        IndexedPQueue {
+            queue: PQueue::with_comparator(compare),
+            handles: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn contains(&self, id: &I) -> bool {
+        self.handles.contains_key(id)
+    }
+
+    /// Insert an item without associated data, or update the item with the ID removing its value.
+    ///
+    /// Returns the previous key and value of the item if the ID was in the queue.
+    pub fn upsert_k(&mut self, id: I, key: K) -> Option<(K, Option<V>)> {
+        self.upsert(id, key, None)
+    }
+
+    /// Insert an item, or update the key and the value of the item with the ID in O(log n).
+    ///
+    /// Returns the previous key and value of the item if the ID was in the queue.
+    pub fn upsert_kv(&mut self, id: I, key: K, value: V) -> Option<(K, Option<V>)> {
+        self.upsert(id, key, Some(value))
+    }
+
+    fn upsert(&mut self, id: I, key: K, value: Option<V>) -> Option<(K, Option<V>)> {
+        match self.handles.get(&id) {
+            Some(handle) => {
+                let handle = *handle;
+                let entry = self.queue.value_mut_by_handle(handle).expect(VALID_HANDLES).as_mut();
+                let old_value = mem::replace(&mut Self::expect_entry(entry).1, value);
+                let old_key = self.queue.replace_key(handle, key).expect(VALID_HANDLES);
+                Some((old_key, old_value))
+            }
+            None => {
+                let handle = self.queue.insert_kv(key, (id.clone(), value));
+                self.handles.insert(id, handle);
+                None
+            }
+        }
+    }
+
+    /// Change the key of the item with the ID, keeping its value.
+    ///
+    /// Fails with `PQueueError::UnknownId` if no item has the ID.
+    pub fn update_priority(&mut self, id: &I, key: K) -> Result<(), PQueueError> {
+        let handle = *self.handles.get(id).ok_or(PQueueError::UnknownId)?;
+        self.queue.update_key(handle, key)
+    }
+
+    /// The key of the item with the ID.
+    pub fn priority_of(&self, id: &I) -> Option<&K> {
+        self.get(id).map(|(key, _)| key)
+    }
+
+    /// The key and the value of the item with the ID.
+    pub fn get(&self, id: &I) -> Option<(&K, Option<&V>)> {
+        let handle = self.handles.get(id)?;
+        let (key, entry) = self.queue.get_by_handle(*handle).expect(VALID_HANDLES);
+        Some((key, Self::expect_entry(entry).1.as_ref()))
+    }
+
+    /// Remove the item with the ID in O(log n), returning its key and value.
+    pub fn remove(&mut self, id: &I) -> Option<(K, Option<V>)> {
+        let handle = self.handles.remove(id)?;
+        let (key, entry) = self.queue.remove(handle).expect(VALID_HANDLES);
+        Some((key, Self::expect_entry(entry).1))
+    }
+
+    /// Return the ID, the key and the value of the item with higher priority without removing it.
+    pub fn top(&self) -> Option<(&I, &K, Option<&V>)> {
+        let (key, (id, value)) = self.queue.top_kv().map(|(key, entry)| (key, Self::expect_entry(entry)))?;
+        Some((id, key, value.as_ref()))
+    }
+
+    /// Pop the item with higher priority, returning its ID, key and value.
+    pub fn pop(&mut self) -> Option<(I, K, Option<V>)> {
+        let (key, entry) = self.queue.pop_kv()?;
+        let (id, value) = Self::expect_entry(entry);
+        self.handles.remove(&id);
+        Some((id, key, value))
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.handles.clear();
+    }
+
+    // Every item is inserted with its ID as the value of the inner queue.
+    fn expect_entry<T>(entry: Option<T>) -> T {
+        entry.expect("items of IndexedPQueue always have an ID")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upsert_and_pop() {
+        let mut queue: IndexedPQueue<String, u32, &str> = IndexedPQueue::new(Priority::Asc);
+        assert_eq!(queue.upsert_kv(String::from("a"), 5, "first"), None);
+        assert_eq!(queue.upsert_kv(String::from("b"), 3, "second"), None);
+        queue.upsert_k(String::from("c"), 4);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.top(), Some((&String::from("b"), &3, Some(&"second"))));
+        assert_eq!(queue.upsert_kv(String::from("a"), 1, "updated"), Some((5, Some("first"))));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some((String::from("a"), 1, Some("updated"))));
+        assert!(!queue.contains(&String::from("a")));
+        assert_eq!(queue.pop(), Some((String::from("b"), 3, Some("second"))));
+        assert_eq!(queue.pop(), Some((String::from("c"), 4, None)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn update_priority() {
+        let mut queue: IndexedPQueue<u32, i32, ()> = IndexedPQueue::new(Priority::Desc);
+        for id in 0..10 {
+            queue.upsert_kv(id, id as i32, ());
+        }
+        assert_eq!(queue.update_priority(&2, 50), Ok(()));
+        assert_eq!(queue.update_priority(&9, -1), Ok(()));
+        assert_eq!(queue.update_priority(&10, 7), Err(PQueueError::UnknownId));
+        assert_eq!(queue.priority_of(&2), Some(&50));
+        assert_eq!(queue.priority_of(&10), None);
+        let ids: Vec<u32> = std::iter::from_fn(|| queue.pop()).map(|(id, _, _)| id).collect();
+        assert_eq!(ids, [2, 8, 7, 6, 5, 4, 3, 1, 0, 9]);
+    }
+
+    #[test]
+    fn get_and_remove() {
+        let mut queue: IndexedPQueue<&str, u8, String> = IndexedPQueue::new(Priority::Asc);
+        queue.upsert_kv("x", 2, String::from("two"));
+        queue.upsert_kv("y", 1, String::from("one"));
+        queue.upsert_k("z", 3);
+        assert_eq!(queue.get(&"x"), Some((&2, Some(&String::from("two")))));
+        assert_eq!(queue.get(&"z"), Some((&3, None)));
+        assert_eq!(queue.remove(&"y"), Some((1, Some(String::from("one")))));
+        assert_eq!(queue.remove(&"y"), None);
+        assert_eq!(queue.get(&"y"), None);
+        assert_eq!(queue.upsert_k("x", 0), Some((2, Some(String::from("two")))));
+        assert_eq!(queue.get(&"x"), Some((&0, None)));
+        assert_eq!(queue.top(), Some((&"x", &0, None)));
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(!queue.contains(&"z"));
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let mut queue: IndexedPQueue<char, (u8, u8), ()> =
+            IndexedPQueue::with_comparator(|a: &(u8, u8), b: &(u8, u8)| a.1.cmp(&b.1));
+        queue.upsert_k('a', (1, 9));
+        queue.upsert_k('b', (9, 1));
+        assert_eq!(queue.pop().map(|(id, _, _)| id), Some('b'));
+    }
+}
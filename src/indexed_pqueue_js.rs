@@ -0,0 +1,104 @@
+use js_sys::{Array, Error, TypeError};
+use wasm_bindgen::prelude::*;
+
+use crate::indexed_pqueue::IndexedPQueue;
+use crate::pqueue::Priority;
+use crate::pqueue_js::{pair_to_array, NumberJs};
+
+
+// An array with the ID followed by the elements of `pair_to_array`.
+fn entry_to_array(id: &str, key: &NumberJs, value: Option<&JsValue>) -> Array {
+    let array = pair_to_array(key, value);
+    array.unshift(&JsValue::from_str(id));
+    array
+}
+
+#[wasm_bindgen( js_name = IndexedPQueue )]
+pub struct IndexedPQueueJs {
+    queue: IndexedPQueue<String, NumberJs, JsValue>,
+}
+
+#[wasm_bindgen( js_class = IndexedPQueue )]
+impl IndexedPQueueJs {
+    #[wasm_bindgen(constructor)]
+    /// Construct a new priority queue whose keys are identified by string IDs.
+    ///
+    /// Like in `PQueue`, the `order` should be "asc" or "desc", otherwise, it throws a exception.
+    pub fn new(order: String) -> Result<IndexedPQueueJs, TypeError> {
+        let priority = match order.as_str() {
+            "asc" => Priority::Asc,
+            "desc" => Priority::Desc,
+            _ => return Err(TypeError::new("The order parameter should be asc or desc")),
+        };
+        Ok(IndexedPQueueJs { queue: IndexedPQueue::new(priority) })
+    }
+
+    #[wasm_bindgen(getter)]
+    /// The number of keys in the queue.
+    pub fn length(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Insert the key with the ID, or update the key and the value of the ID if it's already in the queue.
+    ///
+    /// If the `value` is `undefined`, the key has no associated data.
+    /// Returns the previous pair key/value of the ID, or `undefined` if the ID wasn't in the queue.
+    pub fn set(&mut self, id: String, key_op: Option<f64>, value: JsValue) -> Result<Option<Array>, TypeError> {
+        let key = NumberJs::new(key_op.ok_or_else(|| TypeError::new("set expect a number"))?);
+        let previous = if value.is_undefined() {
+            self.queue.upsert_k(id, key)
+        } else {
+            self.queue.upsert_kv(id, key, value)
+        };
+        Ok(previous.map(|(key, value)| pair_to_array(&key, value.as_ref())))
+    }
+
+    #[wasm_bindgen( js_name = updatePriority )]
+    /// Change the key of the ID, keeping its value. If the ID isn't in the queue, an error is thrown.
+    pub fn update_priority(&mut self, id: String, key_op: Option<f64>) -> Result<(), JsValue> {
+        let key = NumberJs::new(key_op.ok_or_else(|| TypeError::new("updatePriority expect a number"))?);
+        self.queue
+            .update_priority(&id, key)
+            .map_err(|_| Error::new("The ID isn't in the queue").into())
+    }
+
+    /// Return the pair key/value of the ID without removing it, or `undefined` if the ID isn't in the queue.
+    pub fn get(&self, id: String) -> Option<Array> {
+        self.queue
+            .get(&id)
+            .map(|(key, value)| pair_to_array(key, value))
+    }
+
+    /// Whether the ID is in the queue.
+    pub fn has(&self, id: String) -> bool {
+        self.queue.contains(&id)
+    }
+
+    /// Remove the ID from the queue, returning whether it was in the queue.
+    pub fn delete(&mut self, id: String) -> bool {
+        self.queue.remove(&id).is_some()
+    }
+
+    /// Pop from the queue the key with higher priority.
+    ///
+    /// Returns a array with the ID followed by the key, and the value if the key has associated data.
+    pub fn pop(&mut self) -> Result<Array, Error> {
+        self.queue
+            .pop()
+            .map(|(id, key, value)| entry_to_array(&id, &key, value.as_ref()))
+            .ok_or(Error::new("Cannot pop from empty queue"))
+    }
+
+    /// Return the key with higher priority without remove it, the returned array is like the one returned by `pop`.
+    pub fn top(&self) -> Result<Array, Error> {
+        self.queue
+            .top()
+            .map(|(id, key, value)| entry_to_array(id, key, value))
+            .ok_or(Error::new("Cannot call top from empty queue"))
+    }
+
+    /// Remove all the keys of the queue.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
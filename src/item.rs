@@ -1,37 +1,44 @@
 
-use std::rc::Rc;
 use std::cmp;
 
+/// Identifies an item inserted in a `PQueue`, it remains valid until the item leaves the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Handle(pub(crate) usize);
+
 #[derive(Debug, Clone)]
-pub struct Item<K: Copy + cmp::Ord + cmp::Eq, V: Clone> {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Item<K: cmp::Ord, V> {
     pub key: K,
-    pub value: Option<Rc<V>>,
+    pub value: Option<V>,
+    pub handle: Handle,
 }
 
-impl<K: Copy + cmp::Ord + cmp::Eq, V: Clone> cmp::Ord for Item<K, V> {
+impl<K: cmp::Ord, V> cmp::Ord for Item<K, V> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         self.key.cmp(&other.key)
     }
 }
 
-impl<K: Copy + cmp::Ord + cmp::Eq, V: Clone> cmp::PartialOrd for Item<K, V> {
+impl<K: cmp::Ord, V> cmp::PartialOrd for Item<K, V> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        self.key.partial_cmp(&other.key)
+        Some(self.cmp(other))
     }
 }
 
-impl<K: Copy + cmp::Ord + cmp::Eq, V: Clone> cmp::PartialEq for Item<K, V> {
+impl<K: cmp::Ord, V> cmp::PartialEq for Item<K, V> {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
     }
 }
 
-impl<K: Copy + cmp::Ord + cmp::Eq, V: Clone> cmp::Eq for Item<K, V> { }
+impl<K: cmp::Ord, V> cmp::Eq for Item<K, V> { }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
 
     #[test]
     fn item_ordering() {
@@ -39,10 +46,12 @@ mod tests {
         let item1 = Item {
             key: 2,
             value: Some(value.clone()),
+            handle: Handle(0),
         };
         let item2 = Item {
             key: 3,
             value: Some(value),
+            handle: Handle(1),
         };
         assert_eq!(item1.cmp(&item2), cmp::Ordering::Less);
         assert_eq!(item2.cmp(&item1), cmp::Ordering::Greater);
@@ -56,10 +65,12 @@ mod tests {
         let item1 = Item {
             key: 2,
             value: Some(Rc::new(value1)),
+            handle: Handle(0),
         };
         let item2 = Item {
             key: 2,
             value: Some(Rc::new(value2)),
+            handle: Handle(1),
         };
         assert_eq!(item1, item2);
     }
@@ -67,7 +78,7 @@ mod tests {
     #[test]
     fn item_share_data() {
         let value = Rc::new(String::from("item!"));
-        let item1 = Item { key: 2, value: Some(value) };
+        let item1 = Item { key: 2, value: Some(value), handle: Handle(0) };
         let item2 = item1.clone();
         assert_eq!(item1, item2);
         assert_eq!(item1.value.unwrap().as_ptr(), item2.value.unwrap().as_ptr());
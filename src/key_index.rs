@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::item::Handle;
+
+
+/// The handles of the items with each key of a `PQueue`, see `PQueue::indexed`.
+///
+/// The queue keeps it as a trait object, so `Hash` is only required from the keys of indexed queues.
+pub(crate) trait KeyIndex<K>: Send + Sync {
+    fn insert(&mut self, key: &K, handle: Handle);
+    // Removing a handle that isn't indexed does nothing.
+    fn remove(&mut self, key: &K, handle: Handle);
+    fn handles(&self, key: &K) -> &[Handle];
+    fn clear(&mut self);
+    fn new_empty(&self) -> Box<dyn KeyIndex<K>>;
+}
+
+pub(crate) struct HashKeyIndex<K> {
+    handles: HashMap<K, Vec<Handle>>,
+}
+
+impl<K> HashKeyIndex<K> {
+    pub(crate) fn new() -> HashKeyIndex<K> {
+        HashKeyIndex { handles: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> KeyIndex<K> for HashKeyIndex<K> {
+    fn insert(&mut self, key: &K, handle: Handle) {
+        match self.handles.get_mut(key) {
+            Some(handles) => handles.push(handle),
+            None => {
+                self.handles.insert(key.clone(), vec![handle]);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K, handle: Handle) {
+        if let Some(handles) = self.handles.get_mut(key) {
+            if let Some(i) = handles.iter().position(|indexed| *indexed == handle) {
+                handles.swap_remove(i);
+            }
+            if handles.is_empty() {
+                self.handles.remove(key);
+            }
+        }
+    }
+
+    fn handles(&self, key: &K) -> &[Handle] {
+        self.handles.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    fn clear(&mut self) {
+        self.handles.clear();
+    }
+
+    fn new_empty(&self) -> Box<dyn KeyIndex<K>> {
+        Box::new(HashKeyIndex::<K>::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove_handles() {
+        let mut index: HashKeyIndex<String> = HashKeyIndex::new();
+        let key = String::from("job");
+        index.insert(&key, Handle(0));
+        index.insert(&key, Handle(3));
+        index.insert(&String::from("other"), Handle(1));
+        assert_eq!(index.handles(&key), [Handle(0), Handle(3)]);
+        index.remove(&key, Handle(0));
+        index.remove(&key, Handle(7));
+        assert_eq!(index.handles(&key), [Handle(3)]);
+        index.remove(&key, Handle(3));
+        assert!(index.handles(&key).is_empty());
+        assert!(!index.handles.contains_key(&key));
+        index.clear();
+        assert!(index.handles(&String::from("other")).is_empty());
+    }
+}
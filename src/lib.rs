@@ -1,6 +1,40 @@
+// These clippy lints fire on code that predates the clippy checks, in `pqueue_js` and in the tests of `pqueue`.
+// They're allowed for the whole crate, so that code isn't rewritten or annotated only to please the linter.
+#![allow(
+    clippy::init_numbered_fields,
+    clippy::non_canonical_partial_ord_impl,
+    clippy::cmp_owned,
+    clippy::empty_line_after_outer_attr,
+    clippy::needless_borrow,
+    clippy::useless_vec,
+    clippy::assertions_on_constants,
+    clippy::useless_conversion,
+)]
 
+mod clock;
+mod delay_queue;
+mod delay_queue_js;
+mod error;
+mod indexed_pqueue;
+mod indexed_pqueue_js;
 mod item;
+mod key_index;
+mod lowest_heap;
+mod minmax_pqueue;
+mod minmax_pqueue_js;
+mod multi_pqueue;
 mod pqueue;
 mod pqueue_js;
+mod snapshot;
+mod sync_pqueue;
 
-pub use pqueue::{PQueue, Priority};
\ No newline at end of file
+pub use clock::{Clock, SystemClock};
+pub use delay_queue::DelayQueue;
+pub use error::PQueueError;
+pub use indexed_pqueue::IndexedPQueue;
+pub use item::Handle;
+pub use minmax_pqueue::MinMaxPQueue;
+pub use multi_pqueue::MultiPQueue;
+pub use pqueue::{DuplicatePolicy, Offer, PQueue, PeekMut, Priority};
+pub use snapshot::SnapshotCodec;
+pub use sync_pqueue::SyncPQueue;
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::item::Handle;
+
+
+/// A binary heap of the handles of a bounded `PQueue`, whose root is the item with lowest priority.
+///
+/// The items stay in the `PQueue`, so every operation takes `lower(a, b)`, which tells whether the item
+/// identified by `a` has lower priority than the item identified by `b`.
+pub(crate) struct LowestHeap {
+    vec: Vec<Handle>,
+    // Position (1-based) of each handle in `vec`.
+    positions: HashMap<Handle, usize>,
+}
+
+impl LowestHeap {
+    pub(crate) fn new() -> LowestHeap {
+        LowestHeap { vec: Vec::new(), positions: HashMap::new() }
+    }
+
+    /// Build the heap of `handles` bottom-up in O(n).
+    pub(crate) fn from_handles<L: Fn(Handle, Handle) -> bool>(handles: Vec<Handle>, lower: L) -> LowestHeap {
+        let mut heap = LowestHeap { vec: handles, positions: HashMap::new() };
+        for (node_i, handle) in heap.vec.iter().enumerate() {
+            heap.positions.insert(*handle, node_i + 1);
+        }
+        for node_i in (1..=heap.vec.len() / 2).rev() {
+            heap.sift_down(node_i, &lower);
+        }
+        heap
+    }
+
+    pub(crate) fn lowest(&self) -> Option<Handle> {
+        self.vec.first().copied()
+    }
+
+    pub(crate) fn insert<L: Fn(Handle, Handle) -> bool>(&mut self, handle: Handle, lower: L) {
+        self.vec.push(handle);
+        self.positions.insert(handle, self.vec.len());
+        self.sift_up(self.vec.len(), &lower);
+    }
+
+    // Removing a handle that isn't in the heap does nothing.
+    pub(crate) fn remove<L: Fn(Handle, Handle) -> bool>(&mut self, handle: Handle, lower: L) {
+        if let Some(node_i) = self.positions.remove(&handle) {
+            let last = self.vec.pop().expect("the heap has the removed handle");
+            if node_i <= self.vec.len() {
+                self.vec[node_i - 1] = last;
+                self.positions.insert(last, node_i);
+                self.sift_up(node_i, &lower);
+                self.sift_down(self.positions[&last], &lower);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.vec.clear();
+        self.positions.clear();
+    }
+
+    fn swap(&mut self, node_a: usize, node_b: usize) {
+        self.vec.swap(node_a - 1, node_b - 1);
+        self.positions.insert(self.vec[node_a - 1], node_a);
+        self.positions.insert(self.vec[node_b - 1], node_b);
+    }
+
+    fn sift_up<L: Fn(Handle, Handle) -> bool>(&mut self, mut node_i: usize, lower: &L) {
+        while node_i > 1 && lower(self.vec[node_i - 1], self.vec[node_i / 2 - 1]) {
+            self.swap(node_i, node_i / 2);
+            node_i /= 2;
+        }
+    }
+
+    fn sift_down<L: Fn(Handle, Handle) -> bool>(&mut self, mut node_i: usize, lower: &L) {
+        loop {
+            let mut lowest = node_i;
+            for child in [2 * node_i, 2 * node_i + 1] {
+                if child <= self.vec.len() && lower(self.vec[child - 1], self.vec[lowest - 1]) {
+                    lowest = child;
+                }
+            }
+            if lowest == node_i {
+                break;
+            }
+            self.swap(node_i, lowest);
+            node_i = lowest;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowest_handle() {
+        let keys = [5, 1, 9, 3, 7, 9, 0];
+        let lower = |a: Handle, b: Handle| keys[a.0] > keys[b.0];
+        let mut heap = LowestHeap::from_handles((0..4).map(Handle).collect(), lower);
+        assert_eq!(heap.lowest(), Some(Handle(2)));
+        for handle in 4..7 {
+            heap.insert(Handle(handle), lower);
+        }
+        assert!([Some(Handle(2)), Some(Handle(5))].contains(&heap.lowest()));
+        heap.remove(Handle(2), lower);
+        heap.remove(Handle(2), lower);
+        assert_eq!(heap.lowest(), Some(Handle(5)));
+        heap.remove(Handle(5), lower);
+        heap.remove(Handle(0), lower);
+        assert_eq!(heap.lowest(), Some(Handle(4)));
+        heap.clear();
+        assert_eq!(heap.lowest(), None);
+    }
+}
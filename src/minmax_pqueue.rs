@@ -0,0 +1,238 @@
+use std::cmp;
+use std::fmt;
+
+
+/// A double-ended priority queue implemented as a min-max heap.
+///
+/// The nodes in even levels of the heap are smaller than their descendants and the nodes in odd levels
+/// are greater than their descendants, so both the smallest and the greatest key are found in O(1).
+pub struct MinMaxPQueue<K: cmp::Ord, V> {
+    vec: Vec<(K, Option<V>)>,
+}
+
+impl<K: cmp::Ord, V> MinMaxPQueue<K, V> {
+    pub fn new() -> MinMaxPQueue<K, V> {
+        MinMaxPQueue {
+            vec: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    pub fn insert_k(&mut self, key: K) {
+        self.insert(key, None)
+    }
+
+    pub fn insert_kv(&mut self, key: K, value: V) {
+        self.insert(key, Some(value))
+    }
+
+    fn insert(&mut self, key: K, value: Option<V>) {
+        self.vec.push((key, value));
+        let node_i = self.vec.len() - 1;
+        if node_i > 0 {
+            let order = Self::level_order(node_i);
+            let father_i = (node_i - 1) / 2;
+            if self.vec[father_i].0.cmp(&self.vec[node_i].0) == order {
+                // the key belongs to the levels of the father
+                self.vec.swap(father_i, node_i);
+                self.push_up(father_i, order.reverse());
+            } else {
+                self.push_up(node_i, order);
+            }
+        }
+    }
+
+    pub fn top_min(&self) -> Option<(&K, Option<&V>)> {
+        self.vec.first().map(|(key, value)| (key, value.as_ref()))
+    }
+
+    pub fn top_max(&self) -> Option<(&K, Option<&V>)> {
+        self.max_index().map(|node_i| {
+            let (key, value) = &self.vec[node_i];
+            (key, value.as_ref())
+        })
+    }
+
+    pub fn pop_min(&mut self) -> Option<(K, Option<V>)> {
+        if self.vec.is_empty() {
+            None
+        } else {
+            Some(self.remove_node(0))
+        }
+    }
+
+    pub fn pop_max(&mut self) -> Option<(K, Option<V>)> {
+        self.max_index().map(|node_i| self.remove_node(node_i))
+    }
+
+    // The greatest key is the root if it's alone, otherwise it's one of its children.
+    fn max_index(&self) -> Option<usize> {
+        match self.vec.len() {
+            0 => None,
+            1 => Some(0),
+            2 => Some(1),
+            _ => Some(if self.vec[1].0 >= self.vec[2].0 { 1 } else { 2 }),
+        }
+    }
+
+    fn remove_node(&mut self, node_i: usize) -> (K, Option<V>) {
+        let pair = self.vec.swap_remove(node_i);
+        if node_i < self.vec.len() {
+            self.trickle_down(node_i, Self::level_order(node_i));
+        }
+        pair
+    }
+
+    // `Less` for the nodes in min levels, `Greater` for the nodes in max levels.
+    fn level_order(node_i: usize) -> cmp::Ordering {
+        if (node_i + 1).ilog2() & 1 == 0 {
+            cmp::Ordering::Less
+        } else {
+            cmp::Ordering::Greater
+        }
+    }
+
+    // Move up the node through the levels that share its order, comparing it with its grandfathers.
+    fn push_up(&mut self, mut node_i: usize, order: cmp::Ordering) {
+        while node_i > 2 {
+            let grandfather_i = ((node_i - 1) / 2 - 1) / 2;
+            if self.vec[node_i].0.cmp(&self.vec[grandfather_i].0) != order {
+                break;
+            }
+            self.vec.swap(node_i, grandfather_i);
+            node_i = grandfather_i;
+        }
+    }
+
+    fn trickle_down(&mut self, mut node_i: usize, order: cmp::Ordering) {
+        loop {
+            let size = self.vec.len();
+            let first_child = node_i * 2 + 1;
+            if first_child >= size {
+                break;
+            }
+            // the candidates are the children and grandchildren of the node
+            let first_grandchild = first_child * 2 + 1;
+            let mut candidates = (first_child..cmp::min(first_child + 2, size))
+                .chain(first_grandchild..cmp::min(first_grandchild + 4, size));
+            let mut higher_priority = candidates.next().unwrap();
+            for candidate in candidates {
+                if self.vec[candidate].0.cmp(&self.vec[higher_priority].0) == order {
+                    higher_priority = candidate;
+                }
+            }
+            if self.vec[higher_priority].0.cmp(&self.vec[node_i].0) != order {
+                break;
+            }
+            self.vec.swap(node_i, higher_priority);
+            if higher_priority < first_grandchild {
+                break;
+            }
+            let father_i = (higher_priority - 1) / 2;
+            if self.vec[father_i].0.cmp(&self.vec[higher_priority].0) == order {
+                self.vec.swap(father_i, higher_priority);
+            }
+            node_i = higher_priority;
+        }
+    }
+}
+
+impl<K: cmp::Ord, V> Default for MinMaxPQueue<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: cmp::Ord + fmt::Debug, V> fmt::Debug for MinMaxPQueue<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.vec.iter().map(|(key, _)| key)).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_min_max_heap<K: cmp::Ord, V>(queue: &MinMaxPQueue<K, V>) {
+        for node_i in 1..queue.vec.len() {
+            let mut ancestor_i = node_i;
+            while ancestor_i > 0 {
+                ancestor_i = (ancestor_i - 1) / 2;
+                let order = MinMaxPQueue::<K, V>::level_order(ancestor_i);
+                assert_ne!(queue.vec[node_i].0.cmp(&queue.vec[ancestor_i].0), order);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_queue() {
+        let mut queue: MinMaxPQueue<u8, String> = MinMaxPQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.top_min(), None);
+        assert_eq!(queue.top_max(), None);
+        assert_eq!(queue.pop_min(), None);
+        assert_eq!(queue.pop_max(), None);
+    }
+
+    #[test]
+    fn top_min_and_max() {
+        let mut queue: MinMaxPQueue<i32, String> = MinMaxPQueue::new();
+        queue.insert_kv(3, String::from("three"));
+        assert_eq!(queue.top_min(), Some((&3, Some(&String::from("three")))));
+        assert_eq!(queue.top_max(), Some((&3, Some(&String::from("three")))));
+        queue.insert_k(-1);
+        queue.insert_k(10);
+        queue.insert_k(5);
+        assert_eq!(queue.top_min(), Some((&-1, None)));
+        assert_eq!(queue.top_max(), Some((&10, None)));
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn pop_min_and_max() {
+        let mut queue: MinMaxPQueue<i32, i32> = MinMaxPQueue::new();
+        for n in 0..100 {
+            let key = (n * 37) % 100;
+            queue.insert_kv(key, -key);
+            assert_min_max_heap(&queue);
+        }
+        for n in 0..50 {
+            assert_eq!(queue.pop_min(), Some((n, Some(-n))));
+            assert_min_max_heap(&queue);
+            assert_eq!(queue.pop_max(), Some((99 - n, Some(n - 99))));
+            assert_min_max_heap(&queue);
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_with_repeated_keys() {
+        let mut queue: MinMaxPQueue<u8, String> = MinMaxPQueue::new();
+        let keys: Vec<u8> = (0..200).map(|n| ((n * 13) % 7) as u8).collect();
+        for key in keys.iter() {
+            queue.insert_k(*key);
+        }
+        assert_min_max_heap(&queue);
+        let mut sorted = keys.clone();
+        sorted.sort();
+        let mut popped_max = Vec::new();
+        let mut popped_min = Vec::new();
+        while !queue.is_empty() {
+            popped_max.push(queue.pop_max().unwrap().0);
+            if let Some((key, _)) = queue.pop_min() {
+                popped_min.push(key);
+            }
+            assert_min_max_heap(&queue);
+        }
+        popped_max.reverse();
+        popped_min.extend(popped_max);
+        assert_eq!(popped_min, sorted);
+    }
+}
@@ -0,0 +1,100 @@
+use js_sys::{Array, Error, TypeError};
+use wasm_bindgen::prelude::*;
+
+use crate::minmax_pqueue::MinMaxPQueue;
+use crate::pqueue_js::{pair_to_array, NumberJs};
+
+
+#[wasm_bindgen( js_name = MinMaxPQueue )]
+pub struct MinMaxPQueueJs {
+    queue: MinMaxPQueue<NumberJs, JsValue>,
+}
+
+#[wasm_bindgen( js_class = MinMaxPQueue )]
+impl MinMaxPQueueJs {
+    #[wasm_bindgen(constructor)]
+    /// Construct a new double-ended priority queue, both the smallest and the greatest keys can be popped.
+    pub fn new() -> MinMaxPQueueJs {
+        MinMaxPQueueJs { queue: MinMaxPQueue::new() }
+    }
+
+    #[wasm_bindgen(getter)]
+    /// The number of keys in the queue.
+    pub fn length(&self) -> usize {
+        self.queue.len()
+    }
+
+    #[wasm_bindgen( js_name = insertK )]
+    /// Insert a key in the queue.
+    ///
+    /// The method assumes that there is no data associated with the inserted key.
+    pub fn insert_k(&mut self, key_op: Option<f64>) -> Result<(), TypeError> {
+        match key_op {
+            Some(key) => {
+                self.queue.insert_k(NumberJs::new(key));
+                Ok(())
+            }
+            None => Err(TypeError::new("insertK expect a number")),
+        }
+    }
+
+    #[wasm_bindgen( js_name = insertKV )]
+    /// Insert a key in the queue with the associated `value`.
+    pub fn insert_kv(&mut self, key_op: Option<f64>, value: JsValue) -> Result<(), TypeError> {
+        match key_op {
+            Some(key) => {
+                self.queue.insert_kv(NumberJs::new(key), value);
+                Ok(())
+            }
+            None => Err(TypeError::new("insertKV expect a number")),
+        }
+    }
+
+    #[wasm_bindgen( js_name = popMin )]
+    /// Pop from the queue the pair key/value with the smallest key.
+    ///
+    /// Returns a array which the first element is the key.
+    /// The returned array have the length 2 if the key has inserted with associated data.
+    /// If the key has inserted without any associated data, it returns a array with a single element.
+    pub fn pop_min(&mut self) -> Result<Array, Error> {
+        self.queue
+            .pop_min()
+            .map(|(key, value)| pair_to_array(&key, value.as_ref()))
+            .ok_or(Error::new("Cannot pop from empty queue"))
+    }
+
+    #[wasm_bindgen( js_name = popMax )]
+    /// Pop from the queue the pair key/value with the greatest key.
+    ///
+    /// The returned array is like the one returned by `popMin`.
+    pub fn pop_max(&mut self) -> Result<Array, Error> {
+        self.queue
+            .pop_max()
+            .map(|(key, value)| pair_to_array(&key, value.as_ref()))
+            .ok_or(Error::new("Cannot pop from empty queue"))
+    }
+
+    #[wasm_bindgen( js_name = topMin )]
+    /// Return the pair key/value with the smallest key without remove it.
+    pub fn top_min(&self) -> Result<Array, Error> {
+        self.queue
+            .top_min()
+            .map(|(key, value)| pair_to_array(key, value))
+            .ok_or(Error::new("Cannot call top from empty queue"))
+    }
+
+    #[wasm_bindgen( js_name = topMax )]
+    /// Return the pair key/value with the greatest key without remove it.
+    pub fn top_max(&self) -> Result<Array, Error> {
+        self.queue
+            .top_max()
+            .map(|(key, value)| pair_to_array(key, value))
+            .ok_or(Error::new("Cannot call top from empty queue"))
+    }
+}
+
+impl Default for MinMaxPQueueJs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
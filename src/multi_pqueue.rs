@@ -0,0 +1,273 @@
+use std::cell::Cell;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+use crate::pqueue::{PQueue, Priority};
+
+
+// The number of shards per thread, the `c` of the MultiQueue paper.
+const SHARDS_PER_THREAD: usize = 2;
+
+// How many times `pop_kv` picks two random shards before scanning every shard.
+const RANDOM_POP_ATTEMPTS: usize = 4;
+
+thread_local! {
+    // The state of a xorshift generator, seeded differently in each thread.
+    static RANDOM_STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+fn random_index(len: usize) -> usize {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x % len as u64) as usize
+    })
+}
+
+
+/// A relaxed priority queue for many threads, following the MultiQueue design of Rihani, Sanders and Dementiev.
+///
+/// The items are spread over `c·p` shards, each one a `PQueue` behind its own lock, where `p` is the number
+/// of threads and `c` is 2. Inserting locks a random shard, popping compares the tops of two random shards
+/// and pops the one with higher priority, so threads rarely contend for the same lock.
+///
+/// The price is that `pop_kv` doesn't always return the item with the highest priority. The rank of the
+/// popped item among the queued items is O(shards) in expectation and O(shards · log(shards)) with high
+/// probability, so it suits algorithms that tolerate a few out-of-order items, like parallel graph searches.
+/// With a single shard it behaves like a `PQueue` behind a `Mutex`.
+pub struct MultiPQueue<K: cmp::Ord, V> {
+    shards: Vec<Mutex<PQueue<K, V>>>,
+}
+
+impl<K: cmp::Ord, V> MultiPQueue<K, V> {
+    /// Construct a queue for `threads` concurrent threads.
+    pub fn new(priority: Priority, threads: usize) -> MultiPQueue<K, V> {
+        MultiPQueue::with_shards(priority, SHARDS_PER_THREAD * threads)
+    }
+
+    /// Construct a queue with the given number of shards, more shards reduce contention and increase the rank error.
+    pub fn with_shards(priority: Priority, shards: usize) -> MultiPQueue<K, V> {
+        MultiPQueue::from_queue(PQueue::new(priority), shards)
+    }
+
+    /// Construct a queue ordered by `compare` for `threads` concurrent threads, see `PQueue::with_comparator`.
+    pub fn with_comparator<F>(compare: F, threads: usize) -> MultiPQueue<K, V>
+    where
+        F: Fn(&K, &K) -> cmp::Ordering + Send + Sync + 'static
+    {
+        MultiPQueue::from_queue(PQueue::with_comparator(compare), SHARDS_PER_THREAD * threads)
+    }
+
+    fn from_queue(queue: PQueue<K, V>, shards: usize) -> MultiPQueue<K, V> {
+        assert!(shards > 0, "a MultiPQueue needs at least one shard");
+        let mut queues = Vec::with_capacity(shards);
+        for _ in 1..shards {
+            queues.push(Mutex::new(queue.new_like()));
+        }
+        queues.push(Mutex::new(queue));
+        MultiPQueue { shards: queues }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The number of items, it may be outdated if other threads are inserting or popping.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| Self::lock(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| Self::lock(shard).is_empty())
+    }
+
+    pub fn insert_k(&self, key: K) {
+        self.lock_random_shard().insert_k(key);
+    }
+
+    pub fn insert_kv(&self, key: K, value: V) {
+        self.lock_random_shard().insert_kv(key, value);
+    }
+
+    pub fn pop_k(&self) -> Option<K> {
+        self.pop_kv().map(|(key, _)| key)
+    }
+
+    /// Pop an item with high priority, but not necessarily the highest one.
+    ///
+    /// It returns `None` only if every shard was empty when it was checked.
+    pub fn pop_kv(&self) -> Option<(K, Option<V>)> {
+        for _ in 0..RANDOM_POP_ATTEMPTS {
+            let mut first = self.lock_random_shard();
+            let popped = match self.try_lock(random_index(self.shards.len())) {
+                Some(mut second) if Self::has_higher_top(&second, &first) => second.pop_kv(),
+                _ => first.pop_kv(),
+            };
+            if popped.is_some() {
+                return popped;
+            }
+        }
+        // the random shards were empty, the queue may be almost empty
+        let start = random_index(self.shards.len());
+        (0..self.shards.len())
+            .map(|offset| (start + offset) % self.shards.len())
+            .find_map(|shard_i| Self::lock(&self.shards[shard_i]).pop_kv())
+    }
+
+    fn has_higher_top(queue: &PQueue<K, V>, other: &PQueue<K, V>) -> bool {
+        match (queue.top_k(), other.top_k()) {
+            (Some(key), Some(other_key)) => queue.compare_keys(key, other_key) == cmp::Ordering::Less,
+            (top, other_top) => top.is_some() && other_top.is_none(),
+        }
+    }
+
+    // Keep picking random shards until one isn't locked by another thread.
+    fn lock_random_shard(&self) -> MutexGuard<'_, PQueue<K, V>> {
+        loop {
+            if let Some(shard) = self.try_lock(random_index(self.shards.len())) {
+                return shard;
+            }
+        }
+    }
+
+    fn try_lock(&self, shard_i: usize) -> Option<MutexGuard<'_, PQueue<K, V>>> {
+        match self.shards[shard_i].try_lock() {
+            Ok(shard) => Some(shard),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => panic!("a thread panicked while holding a shard lock"),
+        }
+    }
+
+    fn lock(shard: &Mutex<PQueue<K, V>>) -> MutexGuard<'_, PQueue<K, V>> {
+        shard.lock().expect("a thread panicked while holding a shard lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn shared_between_threads() {
+        assert_send_sync::<MultiPQueue<u32, String>>();
+    }
+
+    #[test]
+    fn shards_per_thread() {
+        let queue: MultiPQueue<u32, ()> = MultiPQueue::new(Priority::Asc, 4);
+        assert_eq!(queue.shards(), 8);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop_kv(), None);
+    }
+
+    #[test]
+    fn single_shard_is_exact() {
+        let queue: MultiPQueue<i32, String> = MultiPQueue::with_shards(Priority::Desc, 1);
+        for key in [3, 9, -2, 5] {
+            queue.insert_kv(key, key.to_string());
+        }
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.pop_kv(), Some((9, Some(String::from("9")))));
+        assert_eq!(queue.pop_k(), Some(5));
+        assert_eq!(queue.pop_k(), Some(3));
+        assert_eq!(queue.pop_k(), Some(-2));
+        assert_eq!(queue.pop_k(), None);
+    }
+
+    #[test]
+    fn custom_comparator_in_every_shard() {
+        let queue: MultiPQueue<i32, ()> = MultiPQueue::with_comparator(|a: &i32, b: &i32| a.abs().cmp(&b.abs()), 1);
+        for key in [-4, 1, -3, 2] {
+            queue.insert_k(key);
+        }
+        let mut keys: Vec<i32> = std::iter::from_fn(|| queue.pop_k()).collect();
+        assert_eq!(keys.len(), 4);
+        keys.sort_by_key(|key| key.abs());
+        assert_eq!(keys, [1, 2, -3, -4]);
+    }
+
+    #[test]
+    fn pops_every_item() {
+        let queue: MultiPQueue<u32, ()> = MultiPQueue::with_shards(Priority::Asc, 16);
+        for key in 0..1_000 {
+            queue.insert_k(key);
+        }
+        let popped: HashSet<u32> = std::iter::from_fn(|| queue.pop_k()).collect();
+        assert_eq!(popped, (0..1_000).collect());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn rank_error_is_bounded() {
+        let shards = 8;
+        let queue: MultiPQueue<u32, ()> = MultiPQueue::with_shards(Priority::Asc, shards);
+        for key in 0..10_000 {
+            queue.insert_k(key);
+        }
+        let mut queued: Vec<u32> = (0..10_000).collect();
+        let mut total_rank = 0;
+        while let Some(key) = queue.pop_k() {
+            let rank = queued.binary_search(&key).unwrap();
+            queued.remove(rank);
+            total_rank += rank;
+        }
+        // the expected rank is O(shards), allow a generous constant
+        assert!(total_rank / 10_000 < 4 * shards, "mean rank {}", total_rank / 10_000);
+    }
+
+    #[test]
+    fn stress_producers_and_consumers() {
+        let threads = 4;
+        let per_thread = 5_000;
+        let queue: Arc<MultiPQueue<u32, u32>> = Arc::new(MultiPQueue::new(Priority::Desc, threads));
+        let producers: Vec<_> = (0..threads as u32)
+            .map(|producer| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    for n in 0..per_thread {
+                        let key = producer * per_thread + n;
+                        queue.insert_kv(key, key);
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    while popped.len() < per_thread as usize {
+                        if let Some((key, value)) = queue.pop_kv() {
+                            assert_eq!(Some(key), value);
+                            popped.push(key);
+                        } else {
+                            thread::yield_now();
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut popped = HashSet::new();
+        for consumer in consumers {
+            for key in consumer.join().unwrap() {
+                assert!(popped.insert(key), "the key {} was popped twice", key);
+            }
+        }
+        assert_eq!(popped.len(), threads * per_thread as usize);
+        assert!(queue.is_empty());
+    }
+}
@@ -1,66 +1,803 @@
-
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::fmt;
 use std::cmp;
 
-use crate::item::{Item};
+use crate::clock::{Clock, SystemClock};
+use crate::error::PQueueError;
+use crate::item::{Handle, Item};
+use crate::key_index::{HashKeyIndex, KeyIndex};
+use crate::lowest_heap::LowestHeap;
+use crate::snapshot::{self, ChecksumReader, ChecksumWriter, SnapshotCodec};
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Priority {Asc, Desc}
 
-pub struct PQueue<K: cmp::Ord + Copy + fmt::Display, V: Clone> {
+/// The outcome of inserting in a bounded queue, see `PQueue::with_max_len`, or in a queue with unique keys,
+/// see `PQueue::unique`.
+#[derive(Debug, PartialEq)]
+pub enum Offer<K, V> {
+    /// The item was inserted and the queue wasn't full.
+    Inserted(Handle),
+    /// The item was inserted evicting the item with the lowest priority, or the item with the same key.
+    Evicted(Handle, (K, Option<V>)),
+    /// The queue is full and the item doesn't have higher priority than the retained items,
+    /// or another item has the same key.
+    Rejected((K, Option<V>)),
+}
+
+/// What happens when inserting a key that is already in a queue with unique keys, see `PQueue::unique`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicatePolicy {
+    /// Keep the item in the queue, rejecting the inserted one.
+    Reject,
+    /// Replace the item in the queue with the inserted one.
+    Replace,
+    /// Keep the item with higher priority, which is the item in the queue if both have the same priority.
+    KeepBetter,
+}
+
+type CompareFn<K> = dyn Fn(&K, &K) -> cmp::Ordering + Send + Sync;
+
+type ExpiredFn<K, V> = dyn FnMut(K, Option<V>) + Send + Sync;
+type ExpiryClock = dyn Clock<Instant> + Send + Sync;
+
+// How keys are compared, the item whose key compares as `Less` is popped first.
+enum Comparator<K> {
+    Asc,
+    Desc,
+    Custom(Arc<CompareFn<K>>),
+}
+
+impl<K: cmp::Ord> Comparator<K> {
+    fn compare(&self, key_a: &K, key_b: &K) -> cmp::Ordering {
+        match self {
+            Comparator::Asc => key_a.cmp(key_b),
+            Comparator::Desc => key_b.cmp(key_a),
+            Comparator::Custom(compare) => compare(key_a, key_b),
+        }
+    }
+
+    // Stable queues break ties between equal keys by insertion order.
+    fn compare_items<V>(&self, stable: bool, item_a: &Item<K, V>, item_b: &Item<K, V>) -> cmp::Ordering {
+        match self.compare(&item_a.key, &item_b.key) {
+            cmp::Ordering::Equal if stable => item_a.handle.0.cmp(&item_b.handle.0),
+            order => order,
+        }
+    }
+}
+
+/// A mutable reference to the item with higher priority of a `PQueue`, returned by `PQueue::top_mut`.
+///
+/// Modifying the key with `key_mut` costs a single sift down when the guard is dropped,
+/// instead of popping and inserting the item again. `PeekMut::pop` removes the item instead.
+pub struct PeekMut<'a, K: cmp::Ord, V, const D: usize = 2> {
+    queue: &'a mut PQueue<K, V, D>,
+    // Whether the key may have changed, modifying only the value keeps the heap valid.
+    sift: bool,
+}
+
+impl<K: cmp::Ord, V, const D: usize> PeekMut<'_, K, V, D> {
+    pub fn key(&self) -> &K {
+        &self.queue.vec[0].key
+    }
+
+    /// # Panics
+    ///
+    /// Panics in a queue with unique keys, since the modified key can't be checked, `PeekMut::set_key` checks it.
+    pub fn key_mut(&mut self) -> &mut K {
+        assert!(self.queue.duplicates.is_none(), "key_mut can't modify the keys of a queue with unique keys");
+        self.modify_key()
+    }
+
+    /// Replace the key, returning the previous one. Like `key_mut`, the item is sifted down when the guard is dropped.
+    ///
+    /// In a queue with unique keys, it fails with `PQueueError::DuplicateKey` if another item has the key,
+    /// unless that item has expired: it's discarded like when inserting the key.
+    pub fn set_key(&mut self, key: K) -> Result<K, PQueueError> {
+        // an expired item doesn't keep its key, like when inserting it
+        if let Some(node_i) = self.queue.expired_node_with_key(&key).filter(|node_i| *node_i != 1) {
+            let item = self.queue.remove_node_below_root(node_i);
+            self.queue.notify_expired(item);
+        }
+        if self.queue.duplicates.is_some() && self.queue.nodes_with_key(&key).iter().any(|node_i| *node_i != 1) {
+            return Err(PQueueError::DuplicateKey);
+        }
+        Ok(mem::replace(self.modify_key(), key))
+    }
+
+    fn modify_key(&mut self) -> &mut K {
+        if !self.sift {
+            // the key is indexed again when the guard is dropped
+            self.queue.unindex_key(1);
+            self.sift = true;
+        }
+        &mut self.queue.vec[0].key
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        self.queue.vec[0].value.as_ref()
+    }
+
+    pub fn value_mut(&mut self) -> Option<&mut V> {
+        self.queue.vec[0].value.as_mut()
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.queue.vec[0].handle
+    }
+
+    /// Remove the item from the queue, returning its key and value.
+    pub fn pop(mut this: PeekMut<'_, K, V, D>) -> (K, Option<V>) {
+        this.sift = false;
+        PQueue::<K, V, D>::into_pair(this.queue.remove_node(1))
+    }
+}
+
+impl<K: cmp::Ord, V, const D: usize> Drop for PeekMut<'_, K, V, D> {
+    fn drop(&mut self) {
+        if self.sift {
+            self.queue.index_key(1);
+            self.queue.heapify(1, self.queue.vec.len());
+        }
+    }
+}
+
+/// A priority queue implemented as a `D`-ary heap, by default a binary heap.
+///
+/// Higher arities make the heap shallower, so inserting is faster, but popping compares more children per level.
+pub struct PQueue<K: cmp::Ord, V, const D: usize = 2> {
     vec: Vec<Item<K, V>>,
-    order: cmp::Ordering
+    // Position (1-based, like `node_i`) of each item in `vec`, updated on every swap.
+    positions: HashMap<Handle, usize>,
+    next_handle: usize,
+    comparator: Comparator<K>,
+    max_len: Option<usize>,
+    // Set only for bounded queues, so the item to evict is found in O(1).
+    lowest: Option<LowestHeap>,
+    // Break ties between equal keys by insertion order, handles are given in increasing order.
+    stable: bool,
+    key_index: Option<Box<dyn KeyIndex<K>>>,
+    // Set only for queues with unique keys, which are always indexed.
+    duplicates: Option<DuplicatePolicy>,
+    // Expiry times of the items inserted with `insert_k_expiring` or `insert_kv_expiring`.
+    expirations: HashMap<Handle, Instant>,
+    on_expired: Option<Box<ExpiredFn<K, V>>>,
+    // Tells when the items expire, shared with the queues built by `new_like`.
+    clock: Arc<ExpiryClock>,
 }
 
-impl<K: cmp::Ord + Copy + fmt::Display, V: Clone> PQueue<K, V> {
-    pub fn new(priority: Priority) -> PQueue<K, V> {
-        let order = match priority {
-            Priority::Asc => cmp::Ordering::Less,
-            Priority::Desc => cmp::Ordering::Greater
+impl<K: cmp::Ord, V, const D: usize> PQueue<K, V, D> {
+    const VALID_ARITY: () = assert!(D >= 2, "the arity of the heap should be at least 2");
+
+    pub fn new(priority: Priority) -> PQueue<K, V, D> {
+        let comparator = match priority {
+            Priority::Asc => Comparator::Asc,
+            Priority::Desc => Comparator::Desc
         };
-        let queue = PQueue {
-            vec: Vec::new(),
-            order
+        PQueue::from_comparator(comparator)
+    }
+
+    /// Construct an empty queue ordered like this one, it can be merged with this queue.
+    pub fn new_like(&self) -> PQueue<K, V, D> {
+        let comparator = match &self.comparator {
+            Comparator::Asc => Comparator::Asc,
+            Comparator::Desc => Comparator::Desc,
+            Comparator::Custom(compare) => Comparator::Custom(compare.clone()),
         };
+        let mut queue = PQueue::from_comparator(comparator);
+        queue.set_max_len(self.max_len);
+        queue.stable = self.stable;
+        queue.key_index = self.key_index.as_ref().map(|key_index| key_index.new_empty());
+        queue.duplicates = self.duplicates;
+        queue.clock = Arc::clone(&self.clock);
+        queue
+    }
+
+    /// The priority given in `PQueue::new`, it's `None` for queues built with a custom comparator.
+    pub fn priority(&self) -> Option<Priority> {
+        match self.comparator {
+            Comparator::Asc => Some(Priority::Asc),
+            Comparator::Desc => Some(Priority::Desc),
+            Comparator::Custom(_) => None,
+        }
+    }
+
+    // Queues built with a custom comparator share the order only if they share the comparator,
+    // like the queues built with `new_like`.
+    fn same_order(&self, other: &PQueue<K, V, D>) -> bool {
+        match (&self.comparator, &other.comparator) {
+            (Comparator::Asc, Comparator::Asc) | (Comparator::Desc, Comparator::Desc) => true,
+            (Comparator::Custom(compare), Comparator::Custom(other_compare)) => Arc::ptr_eq(compare, other_compare),
+            _ => false,
+        }
+    }
+
+    /// Construct a queue ordered by `compare`: the key that compares as the smallest is popped first.
+    ///
+    /// `Priority::Asc` and `Priority::Desc` behave like `Ord::cmp` and its reverse.
+    pub fn with_comparator<F>(compare: F) -> PQueue<K, V, D>
+    where
+        F: Fn(&K, &K) -> cmp::Ordering + Send + Sync + 'static
+    {
+        PQueue::from_comparator(Comparator::Custom(Arc::new(compare)))
+    }
+
+    /// Construct a queue holding `keys_values`, the heap is built in O(n) instead of inserting each item.
+    pub fn from_vec(priority: Priority, keys_values: Vec<(K, Option<V>)>) -> PQueue<K, V, D> {
+        let mut queue = PQueue::new(priority);
+        for (key, value) in keys_values {
+            queue.push(key, value);
+        }
+        queue.rebuild();
+        queue
+    }
+
+    fn from_comparator(comparator: Comparator<K>) -> PQueue<K, V, D> {
+        let () = Self::VALID_ARITY;
+        PQueue {
+            vec: Vec::new(),
+            positions: HashMap::new(),
+            next_handle: 0,
+            comparator,
+            max_len: None,
+            lowest: None,
+            stable: false,
+            key_index: None,
+            duplicates: None,
+            expirations: HashMap::new(),
+            on_expired: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Construct a queue retaining at most `max_len` items, the ones with highest priority.
+    ///
+    /// When the queue is full, inserting an item evicts the item with the lowest priority,
+    /// or rejects the inserted item if it doesn't have a higher priority. See `PQueue::offer_kv`.
+    ///
+    /// The item with the lowest priority is tracked by a second heap, so inserting costs O(log n) even when
+    /// the queue is full. Bulk inserts and merges offer each item, so the bound holds while the input is inserted.
+    pub fn with_max_len(max_len: usize, priority: Priority) -> PQueue<K, V, D> {
+        let mut queue = PQueue::new(priority);
+        queue.set_max_len(Some(max_len));
+        queue
+    }
+
+    // Bound the queue, the items already in the queue should fit in `max_len`.
+    fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+        self.lowest = max_len.map(|_| LowestHeap::new());
+        self.rebuild_lowest();
+    }
+
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+
+    /// Make the queue stable: items with equal keys are popped in the order they were inserted.
+    ///
+    /// `PQueue::new(Priority::Asc).stable()` builds an empty stable queue.
+    pub fn stable(mut self) -> PQueue<K, V, D> {
+        self.stable = true;
+        self.rebuild();
+        self.rebuild_lowest();
+        self
+    }
+
+    pub fn is_stable(&self) -> bool {
+        self.stable
+    }
+
+    /// Index the keys of the queue, so `contains_key`, `count_key`, `get` and `remove_key` don't scan every item.
+    ///
+    /// The index is updated on every insertion and removal. The keys should be hashed consistently with their
+    /// order: keys comparing as `Equal` must be equal and have the same hash.
+    pub fn indexed(mut self) -> PQueue<K, V, D>
+    where
+        K: Hash + Clone + Send + Sync + 'static
+    {
+        let mut key_index = HashKeyIndex::new();
+        for item in self.vec.iter() {
+            key_index.insert(&item.key, item.handle);
+        }
+        self.key_index = Some(Box::new(key_index));
+        self
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.key_index.is_some()
+    }
+
+    /// Allow only one item with each key, inserting an existing key follows the `policy`.
+    ///
+    /// The queue is indexed like in `PQueue::indexed`. If the queue already has repeated keys, the policy picks
+    /// which item is kept as if they were inserted one after the other. Bulk inserts and merges insert each item,
+    /// instead of rebuilding the heap.
+    ///
+    /// An expired item doesn't keep its key: inserting the key discards it like `PQueue::pop_kv` does,
+    /// and the inserted item is kept whatever the policy.
+    pub fn unique(self, policy: DuplicatePolicy) -> PQueue<K, V, D>
+    where
+        K: Hash + Clone + Send + Sync + 'static
+    {
+        let mut queue = if self.is_indexed() { self } else { self.indexed() };
+        queue.duplicates = Some(policy);
+        let mut items = mem::take(&mut queue.vec);
+        let mut expirations = mem::take(&mut queue.expirations);
+        queue.clear();
+        items.sort_by_key(|item| item.handle.0);
+        for item in items {
+            let handle = item.handle;
+            queue.offer_item(item);
+            if let (Some(expires_at), true) = (expirations.remove(&handle), queue.contains(handle)) {
+                queue.expirations.insert(handle, expires_at);
+            }
+        }
         queue
     }
 
+    pub fn duplicate_policy(&self) -> Option<DuplicatePolicy> {
+        self.duplicates
+    }
+
+    // Index a restored queue and set its policy, which fails if it has repeated keys or it isn't indexed.
+    fn restore_index(
+        mut self,
+        indexed: bool,
+        duplicates: Option<DuplicatePolicy>,
+    ) -> Result<PQueue<K, V, D>, &'static str>
+    where
+        K: Hash + Clone + Send + Sync + 'static
+    {
+        if indexed {
+            self = self.indexed();
+        }
+        if duplicates.is_some() {
+            if !self.has_unique_keys() {
+                return Err("the queue has unique keys, but a key is repeated or it isn't indexed");
+            }
+            self.duplicates = duplicates;
+        }
+        Ok(self)
+    }
+
+    // Whether the queue is indexed and no key is repeated, so a restored queue can have unique keys.
+    fn has_unique_keys(&self) -> bool {
+        match &self.key_index {
+            Some(key_index) => self.vec.iter().all(|item| key_index.handles(&item.key).len() == 1),
+            None => false,
+        }
+    }
+
+    /// Call `callback` with the expired items discarded by `pop_kv`, for example to log them.
+    ///
+    /// The callback isn't copied by `PQueue::new_like`.
+    pub fn on_expired<F>(mut self, callback: F) -> PQueue<K, V, D>
+    where
+        F: FnMut(K, Option<V>) + Send + Sync + 'static
+    {
+        self.on_expired = Some(Box::new(callback));
+        self
+    }
+
+    /// Read the current time from `clock` to tell whether the items have expired, instead of `Instant::now()`.
+    ///
+    /// Any closure returning an `Instant` is a clock, so tests can control when the items expire.
+    /// The clock is shared with the queues built by `PQueue::new_like`. The items already expiring keep
+    /// the time they have left, measured with the previous clock.
+    pub fn clock<C>(mut self, clock: C) -> PQueue<K, V, D>
+    where
+        C: Clock<Instant> + Send + Sync + 'static
+    {
+        let expires_in = self.expires_in();
+        self.clock = Arc::new(clock);
+        self.set_expires_in(expires_in);
+        self
+    }
+
+    // The time left until each item expires, zero for the expired items.
+    fn expires_in(&self) -> HashMap<Handle, Duration> {
+        if self.expirations.is_empty() {
+            return HashMap::new();
+        }
+        let now = self.clock.now();
+        self.expirations.iter()
+            .map(|(handle, expires_at)| (*handle, expires_at.saturating_duration_since(now)))
+            .collect()
+    }
+
+    // Replace the expirations with the times left from the current time of the clock.
+    fn set_expires_in(&mut self, expires_in: HashMap<Handle, Duration>) {
+        let now = self.clock.now();
+        self.expirations = expires_in.into_iter()
+            .map(|(handle, expires_in)| (handle, now + expires_in))
+            .collect();
+    }
+
     pub fn len(&self) -> usize {
         self.vec.len()
     }
 
-    pub fn insert_k(&mut self, key: K) {
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Insert a key without associated data, returning the handle of the inserted item.
+    ///
+    /// In a bounded queue the returned handle is already invalid if the key was rejected,
+    /// `PQueue::offer_k` returns the rejected or evicted item instead.
+    pub fn insert_k(&mut self, key: K) -> Handle {
         self.insert(key, None)
     }
 
     pub fn bulk_insert_k(&mut self, keys: Vec<K>) {
-        for key in keys {
-            self.insert(key, None)
-        }
+        self.bulk_insert(keys.into_iter().map(|key| (key, None)));
     }
 
     pub fn bulk_insert_kv(&mut self, keys_values: Vec<(K, V)>) {
-        for (key, value) in keys_values {
-            self.insert(key, Some(value))
+        self.bulk_insert(keys_values.into_iter().map(|(key, value)| (key, Some(value))));
+    }
+
+    /// Move all the items of `other` into this queue, leaving `other` empty.
+    ///
+    /// Both queues must have the same priority, otherwise it fails with `PQueueError::PriorityMismatch`.
+    /// The handles of this queue remain valid, while the handles of `other` are invalidated.
+    pub fn append(&mut self, other: &mut PQueue<K, V, D>) -> Result<(), PQueueError> {
+        if !self.same_order(other) {
+            return Err(PQueueError::PriorityMismatch);
+        }
+        let mut items = mem::take(&mut other.vec);
+        let mut expirations = mem::take(&mut other.expirations);
+        other.clear();
+        if self.stable {
+            // the items of `other` are inserted after the items of this queue, keeping their order
+            items.sort_by_key(|item| item.handle.0);
+        }
+        let expires: Vec<Option<Instant>> = items.iter().map(|item| expirations.remove(&item.handle)).collect();
+        let first_handle = self.next_handle;
+        self.bulk_insert(items.into_iter().map(|item| (item.key, item.value)));
+        // the items took consecutive handles in the order they were inserted
+        for (i, expires_at) in expires.into_iter().enumerate() {
+            let handle = Handle(first_handle + i);
+            if let (Some(expires_at), true) = (expires_at, self.contains(handle)) {
+                self.expirations.insert(handle, expires_at);
+            }
         }
+        Ok(())
     }
 
-    pub fn insert_kv(&mut self, key: K, value: V) {
+    /// Consume both queues returning a queue with all their items, see `PQueue::append`.
+    pub fn merge(mut self, mut other: PQueue<K, V, D>) -> Result<PQueue<K, V, D>, PQueueError> {
+        self.append(&mut other)?;
+        Ok(self)
+    }
+
+    // Rebuilding the whole heap costs about 2 * len comparisons, while inserting each item costs
+    // up to log_D(len) comparisons per item, so pick the cheapest one.
+    // Bounded queues and queues with unique keys offer each item, so rejected items are never pushed.
+    fn bulk_insert<I: ExactSizeIterator<Item = (K, Option<V>)>>(&mut self, keys_values: I) {
+        if self.duplicates.is_some() || self.max_len.is_some() {
+            for (key, value) in keys_values {
+                self.offer(key, value);
+            }
+            return;
+        }
+        let inserted = keys_values.len();
+        let len = self.vec.len() + inserted;
+        if len > 0 && 2 * len < inserted * (len.ilog(D) as usize) {
+            for (key, value) in keys_values {
+                self.push(key, value);
+            }
+            self.rebuild();
+        } else {
+            for (key, value) in keys_values {
+                self.push(key, value);
+                self.sift_up(self.vec.len());
+            }
+        }
+    }
+
+    // Recompute the positions of all items after moving them without `swap`.
+    fn reindex(&mut self) {
+        for (node_i, item) in self.vec.iter().enumerate() {
+            self.positions.insert(item.handle, node_i + 1);
+        }
+    }
+
+    pub fn insert_kv(&mut self, key: K, value: V) -> Handle {
         self.insert(key, Some(value))
     }
 
-    fn insert(&mut self, key: K, value: Option<V>) {
-        let rc_value: Option<Rc<V>> = value.map(|rc| Rc::new(rc));
-        self.vec.push(Item {key, value: rc_value});
-        let mut node_i: usize = self.vec.len();
-        if node_i > 1 {
-            let mut father_i = (node_i / 2) as usize;
-            while (node_i > 1) && (self.vec[node_i - 1].cmp(&self.vec[father_i - 1]) == self.order) {
-                self.vec.swap(father_i - 1, node_i - 1);
-                node_i = father_i;
-                father_i = (node_i / 2) as usize;
+    /// Insert a key without associated data that expires at `expires_at`, see `PQueue::insert_kv_expiring`.
+    pub fn insert_k_expiring(&mut self, key: K, expires_at: Instant) -> Handle {
+        self.insert_expiring(key, None, expires_at)
+    }
+
+    /// Insert a key and a value that expire at `expires_at`, returning the handle of the inserted item.
+    ///
+    /// An item expires once `expires_at` is reached: `pop_kv` discards it, and `top_kv`, the key lookups
+    /// like `contains_key` and the sorted vectors skip it. Until `pop_kv` or `purge_expired` removes it,
+    /// the item is still counted by `len` and returned by `iter`.
+    pub fn insert_kv_expiring(&mut self, key: K, value: V, expires_at: Instant) -> Handle {
+        self.insert_expiring(key, Some(value), expires_at)
+    }
+
+    fn insert_expiring(&mut self, key: K, value: Option<V>, expires_at: Instant) -> Handle {
+        let handle = self.insert(key, value);
+        if self.contains(handle) {
+            self.expirations.insert(handle, expires_at);
+        }
+        handle
+    }
+
+    /// When the item identified by `handle` expires, `None` if it doesn't expire or it isn't in the queue.
+    pub fn expires_at(&self, handle: Handle) -> Option<Instant> {
+        self.expirations.get(&handle).copied()
+    }
+
+    pub fn offer_k(&mut self, key: K) -> Offer<K, V> {
+        self.offer(key, None)
+    }
+
+    pub fn offer_kv(&mut self, key: K, value: V) -> Offer<K, V> {
+        self.offer(key, Some(value))
+    }
+
+    fn insert(&mut self, key: K, value: Option<V>) -> Handle {
+        match self.offer(key, value) {
+            Offer::Inserted(handle) | Offer::Evicted(handle, _) => handle,
+            Offer::Rejected(_) => Handle(self.next_handle - 1),
+        }
+    }
+
+    fn offer(&mut self, key: K, value: Option<V>) -> Offer<K, V> {
+        let handle = Handle(self.next_handle);
+        self.next_handle += 1;
+        self.offer_item(Item {key, value, handle})
+    }
+
+    // Insert an item whose handle was already taken, so rejected items also take a handle
+    // and the handle returned by `insert` is never valid for them.
+    fn offer_item(&mut self, item: Item<K, V>) -> Offer<K, V> {
+        if let Some(policy) = self.duplicates {
+            self.discard_expired_key(&item.key);
+            if let Some(node_i) = self.node_with_key(&item.key) {
+                let replace = match policy {
+                    DuplicatePolicy::Reject => false,
+                    DuplicatePolicy::Replace => true,
+                    DuplicatePolicy::KeepBetter => self.compare_items(&item, &self.vec[node_i - 1]) == cmp::Ordering::Less,
+                };
+                if !replace {
+                    return Offer::Rejected(Self::into_pair(item));
+                }
+                let handle = item.handle;
+                let replaced = self.replace_node(node_i, item);
+                return Offer::Evicted(handle, Self::into_pair(replaced));
+            }
+        }
+        match self.max_len {
+            Some(max_len) if self.vec.len() >= max_len => {
+                // a full queue only takes the item if it has higher priority than the lowest one
+                match self.lowest_priority_node() {
+                    Some(lowest_i) if self.compare_items(&item, &self.vec[lowest_i - 1]) == cmp::Ordering::Less => {
+                        let lowest = self.remove_node(lowest_i);
+                        let handle = self.push_item(item);
+                        self.sift_up(self.vec.len());
+                        Offer::Evicted(handle, Self::into_pair(lowest))
+                    }
+                    _ => Offer::Rejected(Self::into_pair(item)),
+                }
+            }
+            _ => {
+                let handle = self.push_item(item);
+                self.sift_up(self.vec.len());
+                Offer::Inserted(handle)
+            }
+        }
+    }
+
+    fn lowest_priority_node(&self) -> Option<usize> {
+        let handle = self.lowest.as_ref()?.lowest()?;
+        Some(self.positions[&handle])
+    }
+
+    // Append an item at the end of the heap without restoring the heap invariant.
+    fn push(&mut self, key: K, value: Option<V>) -> Handle {
+        let handle = Handle(self.next_handle);
+        self.next_handle += 1;
+        self.push_item(Item {key, value, handle})
+    }
+
+    fn push_item(&mut self, item: Item<K, V>) -> Handle {
+        let handle = item.handle;
+        self.vec.push(item);
+        self.positions.insert(handle, self.vec.len());
+        self.index_key(self.vec.len());
+        handle
+    }
+
+    /// Insert a key and pop the item with higher priority, costing a single sift down.
+    ///
+    /// If the key has higher priority than the top, it's returned right away without modifying the queue.
+    /// Like in `pop_kv`, the expired items found before the top are discarded first.
+    /// In a queue with unique keys, if another item has the key, the top stays in the queue: the duplicate policy
+    /// picks which of the two items leaves the queue, and it's returned instead.
+    pub fn push_pop_k(&mut self, key: K) -> (K, Option<V>) {
+        self.push_pop(key, None)
+    }
+
+    pub fn push_pop_kv(&mut self, key: K, value: V) -> (K, Option<V>) {
+        self.push_pop(key, Some(value))
+    }
+
+    /// Pop the item with higher priority and insert a key, costing a single sift down.
+    ///
+    /// Unlike `push_pop_k`, the top is popped even if the key has higher priority.
+    /// If the queue is empty, or only has expired items, the key is inserted and it returns `None`.
+    /// Like in `push_pop_k`, in a queue with unique keys the top stays if an item other than the top has the key,
+    /// and the item dropped by the duplicate policy is returned instead.
+    pub fn replace_top_k(&mut self, key: K) -> Option<(K, Option<V>)> {
+        self.replace_top(key, None)
+    }
+
+    pub fn replace_top_kv(&mut self, key: K, value: V) -> Option<(K, Option<V>)> {
+        self.replace_top(key, Some(value))
+    }
+
+    fn push_pop(&mut self, key: K, value: Option<V>) -> (K, Option<V>) {
+        self.discard_expired_top();
+        self.discard_expired_key(&key);
+        if self.duplicates.is_some() && self.contains_key(&key) {
+            return self.offer_duplicate(key, value);
+        }
+        let item = Item {key, value, handle: Handle(self.next_handle)};
+        match self.vec.first() {
+            Some(top) if self.compare_items(top, &item) == cmp::Ordering::Less => {
+                self.next_handle += 1;
+                Self::into_pair(self.replace_node(1, item))
             }
+            _ => Self::into_pair(item),
+        }
+    }
+
+    fn replace_top(&mut self, key: K, value: Option<V>) -> Option<(K, Option<V>)> {
+        self.discard_expired_top();
+        self.discard_expired_key(&key);
+        if self.vec.is_empty() {
+            self.insert(key, value);
+            return None;
+        }
+        // popping the top removes its key, so only another item with the key is a duplicate
+        if self.duplicates.is_some() && self.nodes_with_key(&key).iter().any(|node_i| *node_i != 1) {
+            return Some(self.offer_duplicate(key, value));
+        }
+        let handle = Handle(self.next_handle);
+        self.next_handle += 1;
+        Some(Self::into_pair(self.replace_node(1, Item {key, value, handle})))
+    }
+
+    // Offer a key that is already in a queue with unique keys, returning the item dropped by the duplicate policy.
+    fn offer_duplicate(&mut self, key: K, value: Option<V>) -> (K, Option<V>) {
+        match self.offer(key, value) {
+            Offer::Evicted(_, replaced) => replaced,
+            Offer::Rejected(rejected) => rejected,
+            Offer::Inserted(_) => unreachable!("the key is already in the queue"),
+        }
+    }
+
+    // Put the item in `node_i` and move it up or down, returning the previous item of the node.
+    fn replace_node(&mut self, node_i: usize, item: Item<K, V>) -> Item<K, V> {
+        let replaced = mem::replace(&mut self.vec[node_i - 1], item);
+        self.forget(&replaced);
+        self.positions.insert(self.vec[node_i - 1].handle, node_i);
+        self.index_key(node_i);
+        self.resift(node_i);
+        replaced
+    }
+
+    /// Change the key of the item identified by `handle`, moving it up or down in the heap.
+    ///
+    /// Fails with `PQueueError::InvalidHandle` if the item isn't in the queue anymore,
+    /// and with `PQueueError::DuplicateKey` if the queue has unique keys and another item has the key.
+    /// An expired item doesn't keep its key, it's discarded like when inserting the key.
+    pub fn update_key(&mut self, handle: Handle, key: K) -> Result<(), PQueueError> {
+        self.replace_key(handle, key).map(|_| ())
+    }
+
+    // Like `update_key`, returning the previous key.
+    pub(crate) fn replace_key(&mut self, handle: Handle, key: K) -> Result<K, PQueueError> {
+        let node_i = *self.positions.get(&handle).ok_or(PQueueError::InvalidHandle)?;
+        // an expired item doesn't keep its key, like when inserting it
+        if let Some(expired_i) = self.expired_node_with_key(&key).filter(|expired_i| *expired_i != node_i) {
+            self.discard_node(expired_i);
+        }
+        let node_i = self.positions[&handle];
+        if self.duplicates.is_some() && self.node_with_key(&key).is_some_and(|other_i| other_i != node_i) {
+            return Err(PQueueError::DuplicateKey);
+        }
+        self.unindex_key(node_i);
+        let old_key = mem::replace(&mut self.vec[node_i - 1].key, key);
+        self.index_key(node_i);
+        self.resift(node_i);
+        Ok(old_key)
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.positions.contains_key(&handle)
+    }
+
+    // The key and value of the item identified by `handle`.
+    pub(crate) fn get_by_handle(&self, handle: Handle) -> Option<(&K, Option<&V>)> {
+        let item = &self.vec[*self.positions.get(&handle)? - 1];
+        Some((&item.key, item.value.as_ref()))
+    }
+
+    // Modifying the value doesn't change the position of the item.
+    pub(crate) fn value_mut_by_handle(&mut self, handle: Handle) -> Option<&mut Option<V>> {
+        let node_i = *self.positions.get(&handle)?;
+        Some(&mut self.vec[node_i - 1].value)
+    }
+
+    /// Whether an item with the key is in the queue, see `PQueue::indexed` to avoid scanning every item.
+    ///
+    /// Like `count_key` and `get`, it skips the expired items that weren't discarded yet.
+    pub fn contains_key(&self, key: &K) -> bool {
+        let now = self.expiry_now();
+        match &self.key_index {
+            Some(key_index) => key_index.handles(key).iter().any(|handle| self.is_alive(self.positions[handle], now)),
+            None => (1..=self.vec.len()).any(|node_i| self.vec[node_i - 1].key == *key && self.is_alive(node_i, now)),
+        }
+    }
+
+    /// The number of items with the key.
+    pub fn count_key(&self, key: &K) -> usize {
+        let now = self.expiry_now();
+        match &self.key_index {
+            Some(key_index) => key_index.handles(key)
+                .iter()
+                .filter(|handle| self.is_alive(self.positions[handle], now))
+                .count(),
+            None => (1..=self.vec.len())
+                .filter(|node_i| self.vec[node_i - 1].key == *key && self.is_alive(*node_i, now))
+                .count(),
+        }
+    }
+
+    /// The values of the items with the key, in no particular order. It's `None` for items without associated data.
+    pub fn get(&self, key: &K) -> Vec<Option<&V>> {
+        let now = self.expiry_now();
+        self.nodes_with_key(key)
+            .into_iter()
+            .filter(|node_i| self.is_alive(*node_i, now))
+            .map(|node_i| self.vec[node_i - 1].value.as_ref())
+            .collect()
+    }
+
+    /// Remove one of the items with the key, in O(log n) if the queue is indexed.
+    pub fn remove_key(&mut self, key: &K) -> Option<(K, Option<V>)> {
+        let node_i = self.node_with_key(key)?;
+        Some(Self::into_pair(self.remove_node(node_i)))
+    }
+
+    fn node_with_key(&self, key: &K) -> Option<usize> {
+        match &self.key_index {
+            Some(key_index) => key_index.handles(key).first().map(|handle| self.positions[handle]),
+            None => self.vec.iter().position(|item| item.key == *key).map(|i| i + 1),
+        }
+    }
+
+    fn nodes_with_key(&self, key: &K) -> Vec<usize> {
+        match &self.key_index {
+            Some(key_index) => key_index.handles(key).iter().map(|handle| self.positions[handle]).collect(),
+            None => (1..=self.vec.len()).filter(|node_i| self.vec[node_i - 1].key == *key).collect(),
         }
     }
 
@@ -92,136 +829,852 @@ impl<K: cmp::Ord + Copy + fmt::Display, V: Clone> PQueue<K, V> {
         elements
     }
 
+    /// Pop the item with higher priority.
+    ///
+    /// The expired items found before it are discarded, passing them to the callback of `PQueue::on_expired`.
     pub fn pop_kv(&mut self) -> Option<(K, Option<V>)> {
-        if let Some(last) = self.vec.pop() {
-            let key;
-            let rc_value;
-            if self.vec.len() > 0 {
-                let first = self.vec[0].clone();
-                self.vec[0] = last;
-                self.heapify(1);
-                key = first.key;
-                rc_value = first.value;
-            } else {
-                key = last.key;
-                rc_value = last.value;
-            }
-            Some((
-                key,
-                match rc_value {
-                    Some(rc) => Rc::try_unwrap(rc).ok(),
-                    None => None,
-                }
-            ))
-        } else {
+        self.discard_expired_top();
+        if self.vec.is_empty() {
             None
+        } else {
+            Some(Self::into_pair(self.remove_node(1)))
         }
     }
 
-    pub fn top_kv(&self) -> Option<(K, Option<Rc<V>>)> {
-        if self.vec.len() == 0 {
-            None
-        } else {
-            let item = self.vec[0].clone();
-            Some((item.key, item.value))
+    /// Remove the items expired at `now` and return them in no particular order.
+    ///
+    /// Like `PQueue::retain`, it costs O(n) and the heap is rebuilt once. The callback of `PQueue::on_expired`
+    /// isn't called, since the removed items are returned.
+    pub fn purge_expired(&mut self, now: Instant) -> Vec<(K, Option<V>)> {
+        if self.expirations.is_empty() {
+            return Vec::new();
         }
+        let mut expirations = mem::take(&mut self.expirations);
+        let expired = self.drain_items(|item| {
+            expirations.get(&item.handle).is_some_and(|expires_at| *expires_at <= now)
+        });
+        expirations.retain(|_, expires_at| *expires_at > now);
+        self.expirations = expirations;
+        expired
     }
 
-    pub fn top_k(&self) -> Option<K> {
-        if self.vec.len() == 0 {
-            None
-        } else {
-            Some(self.vec[0].key)
-        }
-    }
-
-    fn heapify(&mut self, node_i: usize) {
-        let size: usize = self.vec.len();
-        let left: usize = node_i * 2;
-        let right: usize = left + 1;
-        if node_i < size {
-            if left <= size {
-                let node = self.vec[node_i - 1].clone();
-                if right <= size {
-                    // both left and right exists
-                    if (self.vec[left - 1].cmp(&self.vec[node_i - 1]) == self.order) ||
-                            (self.vec[right - 1].cmp(&self.vec[node_i - 1]) == self.order) {
-                        if self.vec[left - 1].cmp(&self.vec[right - 1]) == self.order {
-                            let higher_priority = self.vec[left - 1].clone();
-                            self.vec[node_i - 1] = higher_priority;
-                            self.vec[left - 1] = node;
-                            self.heapify(left);
-                        } else {
-                            let higher_priority = self.vec[right - 1].clone();
-                            self.vec[node_i - 1] = higher_priority;
-                            self.vec[right - 1] = node;
-                            self.heapify(right);
-                        }
-                    }
-                } else {
-                    if self.vec[left - 1].cmp(&self.vec[node_i - 1]) == self.order {
-                        let higher_priority = self.vec[left - 1].clone();
-                        self.vec[node_i - 1] = higher_priority;
-                        self.vec[left - 1] = node;
-                    }
-                }
-            }
+    // Remove the expired items at the root until the top hasn't expired, passing them to the callback.
+    fn discard_expired_top(&mut self) {
+        if self.expirations.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        while self.vec.first().is_some_and(|top| self.is_expired(top, now)) {
+            self.discard_node(1);
         }
     }
 
-    fn to_string(&self, node_i: usize, level: usize) -> String {
-        let identation = if level > 0 {
-            "    ".repeat(level)
-        } else {
-            String::new()
-        };
-        let header = format!("{} - {}, pos: {}",
-            identation,
-            self.vec[node_i - 1].key,
-            node_i
-        );
-        let left: usize = node_i * 2;
-        let right: usize = left + 1;
-        if left <= self.vec.len() {
-            let left_string = self.to_string(left, level + 1);
-            if right <= self.vec.len() {
-                let right_string = self.to_string(right, level + 1);
-                format!("{}\n{}\n{}", header, left_string, right_string)
-            } else {
-                format!("{}\n{}", header, left_string)
-            }
-        } else {
-            header
+    // In a queue with unique keys, discard the item with the key if it has expired, so the key can be inserted again.
+    fn discard_expired_key(&mut self, key: &K) {
+        if let Some(node_i) = self.expired_node_with_key(key) {
+            self.discard_node(node_i);
         }
     }
-}
 
-impl<K: cmp::Ord + Copy + fmt::Display, V: Clone> fmt::Debug for PQueue<K, V> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.vec.len() > 0 {
-            let repr = self.to_string(1, 0);
-            write!(f, "{}", repr.as_str())
-        } else {
-            write!(f, "empty queue")
+    fn expired_node_with_key(&self, key: &K) -> Option<usize> {
+        if self.duplicates.is_none() || self.expirations.is_empty() {
+            return None;
         }
+        let now = self.clock.now();
+        self.node_with_key(key).filter(|node_i| self.is_expired(&self.vec[node_i - 1], now))
     }
 
-}
+    fn discard_node(&mut self, node_i: usize) {
+        let item = self.remove_node(node_i);
+        self.notify_expired(item);
+    }
 
-impl<K: cmp::Ord + Copy + fmt::Display, V: Clone> Iterator for PQueue<K, V> {
-    type Item = (K, Option<V>);
+    fn notify_expired(&mut self, item: Item<K, V>) {
+        let (key, value) = Self::into_pair(item);
+        if let Some(on_expired) = &mut self.on_expired {
+            on_expired(key, value);
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.pop_kv()
+    fn is_expired(&self, item: &Item<K, V>, now: Instant) -> bool {
+        self.expirations.get(&item.handle).is_some_and(|expires_at| *expires_at <= now)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // The current time, or `None` if no item expires, so the lookups don't read the clock.
+    fn expiry_now(&self) -> Option<Instant> {
+        if self.expirations.is_empty() {
+            None
+        } else {
+            Some(self.clock.now())
+        }
+    }
 
-    #[test]
-    fn length_of_the_queue() {
+    fn is_alive(&self, node_i: usize, now: Option<Instant>) -> bool {
+        !now.is_some_and(|now| self.is_expired(&self.vec[node_i - 1], now))
+    }
+
+    /// Remove the item identified by `handle` wherever it is in the heap.
+    ///
+    /// The handle is invalid afterwards, removing it again fails with `PQueueError::InvalidHandle`.
+    pub fn remove(&mut self, handle: Handle) -> Result<(K, Option<V>), PQueueError> {
+        let node_i = *self.positions.get(&handle).ok_or(PQueueError::InvalidHandle)?;
+        Ok(Self::into_pair(self.remove_node(node_i)))
+    }
+
+    fn into_pair(item: Item<K, V>) -> (K, Option<V>) {
+        (item.key, item.value)
+    }
+
+    /// Return the item with higher priority without removing it.
+    ///
+    /// The values are owned by the queue, to keep a value alive after popping it use `Rc<V>` as the value type.
+    /// Expired items are skipped without discarding them, so the more expired items have higher priority,
+    /// the more it costs, until `pop_kv` or `purge_expired` removes them.
+    pub fn top_kv(&self) -> Option<(&K, Option<&V>)> {
+        let item = &self.vec[self.top_node()? - 1];
+        Some((&item.key, item.value.as_ref()))
+    }
+
+    pub fn top_k(&self) -> Option<&K> {
+        self.top_kv().map(|(key, _)| key)
+    }
+
+    // The node with higher priority that hasn't expired, searching the heap from the root past the expired nodes.
+    fn top_node(&self) -> Option<usize> {
+        if self.vec.is_empty() {
+            return None;
+        }
+        if self.expirations.is_empty() {
+            return Some(1);
+        }
+        let now = self.clock.now();
+        let mut candidates = vec![1];
+        while let Some(best) = (0..candidates.len())
+            .reduce(|best, i| if self.higher_priority(candidates[i], candidates[best]) { i } else { best })
+        {
+            let node_i = candidates.swap_remove(best);
+            if !self.is_expired(&self.vec[node_i - 1], now) {
+                return Some(node_i);
+            }
+            let first_child = Self::first_child(node_i);
+            candidates.extend(first_child..cmp::min(first_child + D, self.vec.len() + 1));
+        }
+        None
+    }
+
+    /// Return a guard to modify the item with higher priority in place, `None` if the queue is empty.
+    ///
+    /// If its key was modified, the item is sifted down when the guard is dropped, see `PeekMut`.
+    /// Like in `pop_kv`, the expired items found before it are discarded.
+    pub fn top_mut(&mut self) -> Option<PeekMut<'_, K, V, D>> {
+        self.discard_expired_top();
+        if self.vec.is_empty() {
+            None
+        } else {
+            Some(PeekMut { queue: self, sift: false })
+        }
+    }
+
+    /// Keep only the items for which `keep` returns `true`, the heap is rebuilt once in O(n).
+    pub fn retain<F: FnMut(&K, Option<&V>) -> bool>(&mut self, mut keep: F) {
+        self.drain_filter(|key, value| !keep(key, value));
+    }
+
+    /// Remove and return the items for which `filter` returns `true`, in no particular order.
+    ///
+    /// Like `PQueue::retain`, the heap is rebuilt once after removing all the items.
+    pub fn drain_filter<F: FnMut(&K, Option<&V>) -> bool>(&mut self, mut filter: F) -> Vec<(K, Option<V>)> {
+        self.drain_items(|item| filter(&item.key, item.value.as_ref()))
+    }
+
+    fn drain_items<F: FnMut(&Item<K, V>) -> bool>(&mut self, filter: F) -> Vec<(K, Option<V>)> {
+        let (removed, kept): (Vec<_>, Vec<_>) = mem::take(&mut self.vec)
+            .into_iter()
+            .partition(filter);
+        self.vec = kept;
+        if !removed.is_empty() {
+            // the heap of the lowest items is rebuilt instead of removing each item while the positions are stale
+            let lowest = self.lowest.take();
+            for item in removed.iter() {
+                self.forget(item);
+            }
+            self.reindex();
+            self.rebuild();
+            self.lowest = lowest;
+            self.rebuild_lowest();
+        }
+        removed.into_iter().map(Self::into_pair).collect()
+    }
+
+    /// Remove all the items returning them in no particular order, the queue is empty even if the iterator isn't consumed.
+    pub fn drain(&mut self) -> impl Iterator<Item = (K, Option<V>)> + '_ {
+        self.positions.clear();
+        self.expirations.clear();
+        if let Some(key_index) = &mut self.key_index {
+            key_index.clear();
+        }
+        if let Some(lowest) = &mut self.lowest {
+            lowest.clear();
+        }
+        self.vec.drain(..).map(Self::into_pair)
+    }
+
+    pub fn clear(&mut self) {
+        self.vec.clear();
+        self.positions.clear();
+        self.expirations.clear();
+        if let Some(key_index) = &mut self.key_index {
+            key_index.clear();
+        }
+        if let Some(lowest) = &mut self.lowest {
+            lowest.clear();
+        }
+    }
+
+    /// Iterate over the items without removing them, in the order they are stored in the heap.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, Option<&V>)> {
+        self.vec.iter().map(|item| (&item.key, item.value.as_ref()))
+    }
+
+    /// Consume the queue returning its items in the order they would be popped, sorting them in place.
+    ///
+    /// Like in `pop_kv`, the expired items are discarded, passing them to the callback of `PQueue::on_expired`.
+    pub fn into_sorted_vec(mut self) -> Vec<(K, Option<V>)> {
+        if let Some(now) = self.expiry_now() {
+            for (key, value) in self.purge_expired(now) {
+                if let Some(on_expired) = &mut self.on_expired {
+                    on_expired(key, value);
+                }
+            }
+        }
+        for size in (1..self.vec.len()).rev() {
+            // the item with higher priority goes after the heap that is still unsorted
+            self.swap(1, size + 1);
+            self.heapify(1, size);
+        }
+        self.vec.reverse();
+        self.vec.into_iter().map(Self::into_pair).collect()
+    }
+
+    /// Return a copy of the items in the order they would be popped without changing the queue.
+    ///
+    /// The values are cloned, with `Rc<V>` values they are shared with the queue. The expired items are skipped.
+    pub fn to_sorted_vec(&self) -> Vec<(K, Option<V>)>
+    where
+        K: Clone,
+        V: Clone
+    {
+        let now = self.expiry_now();
+        let mut items: Vec<&Item<K, V>> = (1..=self.vec.len())
+            .filter(|node_i| self.is_alive(*node_i, now))
+            .map(|node_i| &self.vec[node_i - 1])
+            .collect();
+        items.sort_by(|item_a, item_b| self.compare_items(item_a, item_b));
+        items.into_iter().map(|item| (item.key.clone(), item.value.clone())).collect()
+    }
+
+    fn swap(&mut self, node_a: usize, node_b: usize) {
+        self.vec.swap(node_a - 1, node_b - 1);
+        self.positions.insert(self.vec[node_a - 1].handle, node_a);
+        self.positions.insert(self.vec[node_b - 1].handle, node_b);
+    }
+
+    fn remove_node(&mut self, node_i: usize) -> Item<K, V> {
+        let last_i = self.vec.len();
+        self.swap(node_i, last_i);
+        let item = self.vec.pop().expect("remove_node called on empty queue");
+        self.forget(&item);
+        if node_i < last_i {
+            self.resift(node_i);
+        }
+        item
+    }
+
+    // Like `remove_node`, without moving the root, whose key may be modified through `PeekMut`.
+    // The moved item stops below the root, the guard sifts down the root when it's dropped.
+    fn remove_node_below_root(&mut self, node_i: usize) -> Item<K, V> {
+        let last_i = self.vec.len();
+        self.swap(node_i, last_i);
+        let item = self.vec.pop().expect("remove_node_below_root called on empty queue");
+        self.forget(&item);
+        if node_i < last_i {
+            let mut moved_i = node_i;
+            while Self::father(moved_i) > 1 && self.higher_priority(moved_i, Self::father(moved_i)) {
+                self.swap(Self::father(moved_i), moved_i);
+                moved_i = Self::father(moved_i);
+            }
+            if moved_i == node_i {
+                self.heapify(node_i, self.vec.len());
+            }
+        }
+        item
+    }
+
+    // Drop the item from the indexes, after removing it from `vec`.
+    fn forget(&mut self, item: &Item<K, V>) {
+        self.positions.remove(&item.handle);
+        self.expirations.remove(&item.handle);
+        if let Some(key_index) = &mut self.key_index {
+            key_index.remove(&item.key, item.handle);
+        }
+        self.unindex_lowest(item.handle);
+    }
+
+    // Add the key of the item in `node_i` to the key index, if the queue is indexed,
+    // and to the heap of the lowest items, if the queue is bounded.
+    fn index_key(&mut self, node_i: usize) {
+        let item = &self.vec[node_i - 1];
+        if let Some(key_index) = &mut self.key_index {
+            key_index.insert(&item.key, item.handle);
+        }
+        let lower = Self::lower_priority(&self.vec, &self.positions, &self.comparator, self.stable);
+        if let Some(lowest) = &mut self.lowest {
+            lowest.insert(item.handle, lower);
+        }
+    }
+
+    // Remove the key of the item in `node_i` from the indexes before modifying it.
+    fn unindex_key(&mut self, node_i: usize) {
+        let item = &self.vec[node_i - 1];
+        if let Some(key_index) = &mut self.key_index {
+            key_index.remove(&item.key, item.handle);
+        }
+        self.unindex_lowest(item.handle);
+    }
+
+    fn unindex_lowest(&mut self, handle: Handle) {
+        let lower = Self::lower_priority(&self.vec, &self.positions, &self.comparator, self.stable);
+        if let Some(lowest) = &mut self.lowest {
+            lowest.remove(handle, lower);
+        }
+    }
+
+    // Build again the heap of the lowest items of a bounded queue, after moving several items at once.
+    fn rebuild_lowest(&mut self) {
+        if self.lowest.is_some() {
+            let handles = self.vec.iter().map(|item| item.handle).collect();
+            let lower = Self::lower_priority(&self.vec, &self.positions, &self.comparator, self.stable);
+            self.lowest = Some(LowestHeap::from_handles(handles, lower));
+        }
+    }
+
+    // Whether the first item has lower priority than the second one, it takes the fields of the queue
+    // instead of `self`, so the heap of the lowest items can be modified meanwhile.
+    fn lower_priority<'a>(
+        vec: &'a [Item<K, V>],
+        positions: &'a HashMap<Handle, usize>,
+        comparator: &'a Comparator<K>,
+        stable: bool,
+    ) -> impl Fn(Handle, Handle) -> bool + 'a {
+        move |handle_a, handle_b| {
+            let item_a = &vec[positions[&handle_a] - 1];
+            comparator.compare_items(stable, item_a, &vec[positions[&handle_b] - 1]) == cmp::Ordering::Greater
+        }
+    }
+
+    // Whether the item in `node_a` should be popped before the item in `node_b`.
+    fn higher_priority(&self, node_a: usize, node_b: usize) -> bool {
+        self.compare_items(&self.vec[node_a - 1], &self.vec[node_b - 1]) == cmp::Ordering::Less
+    }
+
+    // The item that compares as `Less` has higher priority.
+    fn compare_items(&self, item_a: &Item<K, V>, item_b: &Item<K, V>) -> cmp::Ordering {
+        self.comparator.compare_items(self.stable, item_a, item_b)
+    }
+
+    // The key that compares as `Less` has higher priority, used to compare the tops of several queues.
+    pub(crate) fn compare_keys(&self, key_a: &K, key_b: &K) -> cmp::Ordering {
+        self.comparator.compare(key_a, key_b)
+    }
+
+    // Like `node_i`, positions are 1-based, the children of a node are `first_child..first_child + D`.
+    fn father(node_i: usize) -> usize {
+        (node_i - 2) / D + 1
+    }
+
+    fn first_child(node_i: usize) -> usize {
+        D * (node_i - 1) + 2
+    }
+
+    fn rebuild(&mut self) {
+        if self.vec.len() > 1 {
+            for node_i in (1..=Self::father(self.vec.len())).rev() {
+                self.heapify(node_i, self.vec.len());
+            }
+        }
+    }
+
+    fn resift(&mut self, node_i: usize) {
+        if node_i > 1 && self.higher_priority(node_i, Self::father(node_i)) {
+            self.sift_up(node_i);
+        } else {
+            self.heapify(node_i, self.vec.len());
+        }
+    }
+
+    fn sift_up(&mut self, mut node_i: usize) {
+        while node_i > 1 {
+            let father_i = Self::father(node_i);
+            if !self.higher_priority(node_i, father_i) {
+                break;
+            }
+            self.swap(father_i, node_i);
+            node_i = father_i;
+        }
+    }
+
+    // Sift down the node, considering only the first `size` nodes as part of the heap.
+    fn heapify(&mut self, node_i: usize, size: usize) {
+        let first_child: usize = Self::first_child(node_i);
+        let mut higher_priority = node_i;
+        for child in first_child..cmp::min(first_child + D, size + 1) {
+            if self.higher_priority(child, higher_priority) {
+                higher_priority = child;
+            }
+        }
+        if higher_priority != node_i {
+            self.swap(node_i, higher_priority);
+            self.heapify(higher_priority, size);
+        }
+    }
+}
+
+impl<K: cmp::Ord + SnapshotCodec, V: SnapshotCodec, const D: usize> PQueue<K, V, D> {
+    /// Write a compact binary snapshot of the queue, which `PQueue::read_snapshot` restores.
+    ///
+    /// The snapshot starts with a header holding a magic number, the format version, the priority, the options
+    /// and the length, followed by the heap array and a checksum. Queues with a custom comparator can't be written.
+    ///
+    /// An `Instant` can't be restored in another process, so each expiring item keeps the time it has left
+    /// when the snapshot is written, and `PQueue::read_snapshot` counts it from the time the snapshot is read.
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> io::Result<()> {
+        let priority = self.priority().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a queue with a custom comparator can't be written in a snapshot",
+        ))?;
+        let mut writer = ChecksumWriter::new(writer);
+        writer.write_all(&snapshot::MAGIC)?;
+        snapshot::VERSION.encode(&mut writer)?;
+        priority.encode(&mut writer)?;
+        self.stable.encode(&mut writer)?;
+        self.max_len.encode(&mut writer)?;
+        self.is_indexed().encode(&mut writer)?;
+        self.duplicates.encode(&mut writer)?;
+        D.encode(&mut writer)?;
+        self.next_handle.encode(&mut writer)?;
+        self.vec.len().encode(&mut writer)?;
+        let expires_in = self.expires_in();
+        for item in self.vec.iter() {
+            item.handle.0.encode(&mut writer)?;
+            item.key.encode(&mut writer)?;
+            item.value.encode(&mut writer)?;
+            expires_in.get(&item.handle).copied().encode(&mut writer)?;
+        }
+        writer.finish()
+    }
+
+    /// Restore a queue written by `PQueue::write_snapshot`, keeping the handles of its items valid.
+    ///
+    /// When the checksum matches, the heap array is restored as it was written in O(n), it's only rebuilt
+    /// if the snapshot was written by a queue with a different arity. Corrupted snapshots fail with
+    /// `io::ErrorKind::InvalidData`, and so do the snapshots of indexed queues, which
+    /// `PQueue::read_indexed_snapshot` restores.
+    pub fn read_snapshot<R: Read>(reader: R) -> io::Result<PQueue<K, V, D>> {
+        let (queue, indexed, duplicates) = Self::read_snapshot_parts(reader)?;
+        if indexed || duplicates.is_some() {
+            return Err(snapshot::invalid_data("the queue is indexed, restore it with read_indexed_snapshot"));
+        }
+        Ok(queue)
+    }
+
+    /// Like `PQueue::read_snapshot`, also restoring the index of indexed queues and the policy of queues
+    /// with unique keys, so the keys are hashable.
+    pub fn read_indexed_snapshot<R: Read>(reader: R) -> io::Result<PQueue<K, V, D>>
+    where
+        K: Hash + Clone + Send + Sync + 'static
+    {
+        let (queue, indexed, duplicates) = Self::read_snapshot_parts(reader)?;
+        queue.restore_index(indexed, duplicates).map_err(snapshot::invalid_data)
+    }
+
+    // The queue without its index, and the options to restore it.
+    fn read_snapshot_parts<R: Read>(reader: R) -> io::Result<(PQueue<K, V, D>, bool, Option<DuplicatePolicy>)> {
+        let mut reader = ChecksumReader::new(reader);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != snapshot::MAGIC {
+            return Err(snapshot::invalid_data("it isn't a snapshot of a queue"));
+        }
+        let version = u8::decode(&mut reader)?;
+        if !(1..=snapshot::VERSION).contains(&version) {
+            return Err(snapshot::invalid_data("unsupported snapshot version"));
+        }
+        let mut queue = PQueue::new(Priority::decode(&mut reader)?);
+        queue.stable = bool::decode(&mut reader)?;
+        let max_len: Option<usize> = Option::decode(&mut reader)?;
+        // the first version of the format didn't have the options of indexed queues
+        let indexed = version >= 2 && bool::decode(&mut reader)?;
+        let duplicates: Option<DuplicatePolicy> = if version >= 2 { Option::decode(&mut reader)? } else { None };
+        let arity = usize::decode(&mut reader)?;
+        queue.next_handle = usize::decode(&mut reader)?;
+        let len = usize::decode(&mut reader)?;
+        let mut expires_in = HashMap::new();
+        for node_i in 1..=len {
+            let handle = Handle(usize::decode(&mut reader)?);
+            if handle.0 >= queue.next_handle || queue.positions.insert(handle, node_i).is_some() {
+                return Err(snapshot::invalid_data("invalid handle in the snapshot"));
+            }
+            let key = K::decode(&mut reader)?;
+            let value = Option::decode(&mut reader)?;
+            queue.vec.push(Item { key, value, handle });
+            // nor the time left until the items expire
+            if version >= 2 {
+                if let Some(duration) = Option::<Duration>::decode(&mut reader)? {
+                    expires_in.insert(handle, duration);
+                }
+            }
+        }
+        reader.verify()?;
+        queue.set_expires_in(expires_in);
+        if max_len.is_some_and(|max_len| len > max_len) {
+            return Err(snapshot::invalid_data("the snapshot has more items than its max_len"));
+        }
+        if arity != D {
+            queue.rebuild();
+        }
+        queue.set_max_len(max_len);
+        Ok((queue, indexed, duplicates))
+    }
+}
+
+impl<K: cmp::Ord + fmt::Display, V, const D: usize> PQueue<K, V, D> {
+    fn to_string(&self, node_i: usize, level: usize) -> String {
+        let identation = if level > 0 {
+            "    ".repeat(level)
+        } else {
+            String::new()
+        };
+        let header = format!("{} - {}, pos: {}",
+            identation,
+            self.vec[node_i - 1].key,
+            node_i
+        );
+        let first_child: usize = Self::first_child(node_i);
+        let last_child: usize = cmp::min(first_child + D, self.vec.len() + 1);
+        let mut repr = header;
+        for child in first_child..last_child {
+            repr.push('\n');
+            repr.push_str(&self.to_string(child, level + 1));
+        }
+        repr
+    }
+}
+
+impl<K: cmp::Ord + fmt::Display, V, const D: usize> fmt::Debug for PQueue<K, V, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.vec.is_empty() {
+            let repr = self.to_string(1, 0);
+            write!(f, "{}", repr.as_str())
+        } else {
+            write!(f, "empty queue")
+        }
+    }
+
+}
+
+impl<K: cmp::Ord, V, const D: usize> Iterator for PQueue<K, V, D> {
+    type Item = (K, Option<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop_kv()
+    }
+}
+
+// A queue is stored as its configuration and its items in heap order, keeping the handles valid after loading it.
+// Queues with a custom comparator can't be stored, since the comparator can't be restored.
+// Like in `PQueue::write_snapshot`, the expiring items keep the time they have left instead of their `Instant`.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    #[serde(rename = "PQueue")]
+    struct PQueueRef<'a, K: cmp::Ord, V> {
+        order: Priority,
+        max_len: Option<usize>,
+        stable: bool,
+        indexed: bool,
+        duplicate_policy: Option<DuplicatePolicy>,
+        next_handle: usize,
+        items: &'a [Item<K, V>],
+        expires_in: Vec<(Handle, Duration)>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename = "PQueue")]
+    struct PQueueData<K: cmp::Ord, V> {
+        order: Priority,
+        max_len: Option<usize>,
+        stable: bool,
+        #[serde(default)]
+        indexed: bool,
+        #[serde(default)]
+        duplicate_policy: Option<DuplicatePolicy>,
+        next_handle: usize,
+        items: Vec<Item<K, V>>,
+        #[serde(default)]
+        expires_in: Vec<(Handle, Duration)>,
+    }
+
+    impl<K: cmp::Ord + Serialize, V: Serialize, const D: usize> Serialize for PQueue<K, V, D> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let order = self.priority()
+                .ok_or_else(|| ser::Error::custom("a queue with a custom comparator can't be serialized"))?;
+            PQueueRef {
+                order,
+                max_len: self.max_len,
+                stable: self.stable,
+                indexed: self.is_indexed(),
+                duplicate_policy: self.duplicates,
+                next_handle: self.next_handle,
+                items: &self.vec,
+                expires_in: self.expires_in().into_iter().collect(),
+            }.serialize(serializer)
+        }
+    }
+
+    // The heap is rebuilt on load, so the items are valid in any order, but the handles must be unique.
+    // Like `PQueue::read_snapshot`, it fails for indexed queues, which `PQueue::deserialize_indexed` loads.
+    impl<'de, K, V, const D: usize> Deserialize<'de> for PQueue<K, V, D>
+    where
+        K: cmp::Ord + Deserialize<'de>,
+        V: Deserialize<'de>
+    {
+        fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+            let (queue, indexed, duplicates) = PQueue::deserialize_parts(deserializer)?;
+            if indexed || duplicates.is_some() {
+                return Err(de::Error::custom("the queue is indexed, deserialize it with PQueue::deserialize_indexed"));
+            }
+            Ok(queue)
+        }
+    }
+
+    impl<K: cmp::Ord, V, const D: usize> PQueue<K, V, D> {
+        /// Deserialize a queue, also restoring the index of indexed queues and the policy of queues with unique keys.
+        ///
+        /// It can be used with `#[serde(deserialize_with = "PQueue::deserialize_indexed")]`.
+        pub fn deserialize_indexed<'de, De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error>
+        where
+            K: Hash + Clone + Send + Sync + 'static + Deserialize<'de>,
+            V: Deserialize<'de>
+        {
+            let (queue, indexed, duplicates) = PQueue::deserialize_parts(deserializer)?;
+            queue.restore_index(indexed, duplicates).map_err(de::Error::custom)
+        }
+
+        // The queue without its index, and the options to restore it.
+        fn deserialize_parts<'de, De: Deserializer<'de>>(
+            deserializer: De,
+        ) -> Result<(Self, bool, Option<DuplicatePolicy>), De::Error>
+        where
+            K: Deserialize<'de>,
+            V: Deserialize<'de>
+        {
+            let data = PQueueData::<K, V>::deserialize(deserializer)?;
+            if data.max_len.is_some_and(|max_len| data.items.len() > max_len) {
+                return Err(de::Error::custom("the queue has more items than its max_len"));
+            }
+            let mut queue = PQueue::new(data.order);
+            queue.stable = data.stable;
+            queue.next_handle = data.next_handle;
+            for (node_i, item) in (1..).zip(data.items.iter()) {
+                if item.handle.0 >= data.next_handle {
+                    return Err(de::Error::custom("the queue has a handle greater than next_handle"));
+                }
+                if queue.positions.insert(item.handle, node_i).is_some() {
+                    return Err(de::Error::custom("the queue has a repeated handle"));
+                }
+            }
+            if data.expires_in.iter().any(|(handle, _)| !queue.positions.contains_key(handle)) {
+                return Err(de::Error::custom("the queue has an expiry for a handle that isn't in the queue"));
+            }
+            queue.set_expires_in(data.expires_in.into_iter().collect());
+            queue.vec = data.items;
+            queue.rebuild();
+            queue.set_max_len(data.max_len);
+            Ok((queue, data.indexed, data.duplicate_policy))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::pqueue::tests::assert_heap;
+
+        fn round_trip<K, V>(queue: &PQueue<K, V>) -> PQueue<K, V>
+        where
+            K: cmp::Ord + Serialize + for<'de> Deserialize<'de>,
+            V: Serialize + for<'de> Deserialize<'de>
+        {
+            serde_json::from_str(&serde_json::to_string(queue).unwrap()).unwrap()
+        }
+
+        fn indexed_round_trip<K, V>(queue: &PQueue<K, V>) -> PQueue<K, V>
+        where
+            K: cmp::Ord + Hash + Clone + Send + Sync + 'static + Serialize + for<'de> Deserialize<'de>,
+            V: Serialize + for<'de> Deserialize<'de>
+        {
+            let json = serde_json::to_string(queue).unwrap();
+            PQueue::deserialize_indexed(&mut serde_json::Deserializer::from_str(&json)).unwrap()
+        }
+
+        #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+        struct Job {
+            priority: u8,
+            name: String,
+        }
+
+        #[test]
+        fn round_trip_keys_without_hash() {
+            let mut queue: PQueue<Job, ()> = PQueue::new(Priority::Asc);
+            queue.insert_k(Job { priority: 2, name: String::from("build") });
+            queue.insert_k(Job { priority: 1, name: String::from("test") });
+            let names: Vec<String> = round_trip(&queue).map(|(job, _)| job.name).collect();
+            assert_eq!(names, vec![String::from("test"), String::from("build")]);
+        }
+
+        #[test]
+        fn serialize_priority() {
+            assert_eq!(serde_json::to_string(&Priority::Desc).unwrap(), "\"Desc\"");
+            assert_eq!(serde_json::from_str::<Priority>("\"Asc\"").unwrap(), Priority::Asc);
+        }
+
+        #[test]
+        fn round_trip_keeps_order_and_handles() {
+            let mut queue: PQueue<i32, String> = PQueue::with_max_len(10, Priority::Desc).stable();
+            let handle = queue.insert_kv(4, String::from("four"));
+            for key in [7, 1, 4, 9] {
+                queue.insert_kv(key, key.to_string());
+            }
+            let mut loaded = round_trip(&queue);
+            assert_eq!(loaded.priority(), Some(Priority::Desc));
+            assert_eq!(loaded.max_len(), Some(10));
+            assert!(loaded.is_stable());
+            assert_eq!(loaded.len(), 5);
+            assert!(loaded.contains(handle));
+            let new_handle = loaded.insert_k(0);
+            assert!(!queue.contains(new_handle));
+            assert_eq!(loaded.remove(new_handle), Ok((0, None)));
+            let keys_values: Vec<(i32, Option<String>)> = loaded.collect();
+            assert_eq!(keys_values, [
+                (9, Some(String::from("9"))),
+                (7, Some(String::from("7"))),
+                (4, Some(String::from("four"))),
+                (4, Some(String::from("4"))),
+                (1, Some(String::from("1"))),
+            ]);
+        }
+
+        #[test]
+        fn round_trip_keeps_index() {
+            let mut queue: PQueue<i32, ()> = PQueue::new(Priority::Asc).indexed();
+            queue.bulk_insert_k(vec![5, 2, 5]);
+            assert!(serde_json::from_str::<PQueue<i32, ()>>(&serde_json::to_string(&queue).unwrap()).is_err());
+            let mut loaded = indexed_round_trip(&queue);
+            assert!(loaded.is_indexed());
+            assert_heap(&loaded);
+            assert_eq!(loaded.count_key(&5), 2);
+            assert!(!indexed_round_trip(&PQueue::<i32, ()>::new(Priority::Asc)).is_indexed());
+            assert_eq!(loaded.remove_key(&2), Some((2, None)));
+            let json = r#"{"order":"Asc","max_len":null,"stable":false,"next_handle":0,"items":[]}"#;
+            assert!(!serde_json::from_str::<PQueue<u8, ()>>(json).unwrap().is_indexed());
+        }
+
+        #[test]
+        fn round_trip_keeps_time_left_to_expire() {
+            let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc);
+            let now = Instant::now();
+            queue.insert_k_expiring(1, now);
+            let expiring = queue.insert_k_expiring(2, now + Duration::from_secs(3600));
+            queue.insert_k(3);
+            let mut loaded = round_trip(&queue);
+            let expires_in = loaded.expires_at(expiring).unwrap().duration_since(Instant::now());
+            assert!(expires_in > Duration::from_secs(3590) && expires_in <= Duration::from_secs(3600));
+            assert_eq!(loaded.bulk_pop_k(3), vec![2, 3]);
+            let missing = r#"{"order":"Asc","max_len":null,"stable":false,"next_handle":1,"items":[],
+                "expires_in":[[0,{"secs":1,"nanos":0}]]}"#;
+            assert!(serde_json::from_str::<PQueue<u8, ()>>(missing).is_err());
+        }
+
+        #[test]
+        fn round_trip_keeps_duplicate_policy() {
+            let mut queue: PQueue<i32, String> = PQueue::new(Priority::Desc).unique(DuplicatePolicy::Reject);
+            queue.insert_kv(1, String::from("one"));
+            queue.insert_kv(2, String::from("two"));
+            let mut loaded = indexed_round_trip(&queue);
+            assert_eq!(loaded.duplicate_policy(), Some(DuplicatePolicy::Reject));
+            loaded.insert_kv(1, String::from("again"));
+            assert_eq!(loaded.count_key(&1), 1);
+            assert_eq!(loaded.get(&1), vec![Some(&String::from("one"))]);
+            let repeated = r#"{"order":"Asc","max_len":null,"stable":false,"indexed":true,"duplicate_policy":"Replace",
+                "next_handle":2,"items":[{"key":1,"value":null,"handle":0},{"key":1,"value":null,"handle":1}]}"#;
+            let from_json = |json| PQueue::<u8, ()>::deserialize_indexed(&mut serde_json::Deserializer::from_str(json));
+            assert!(from_json(repeated).is_err());
+            let not_indexed = r#"{"order":"Asc","max_len":null,"stable":false,"duplicate_policy":"Reject",
+                "next_handle":0,"items":[]}"#;
+            assert!(from_json(not_indexed).is_err());
+        }
+
+        #[test]
+        fn custom_comparator_is_not_serializable() {
+            let mut queue: PQueue<i32, ()> = PQueue::with_comparator(|a: &i32, b: &i32| a.abs().cmp(&b.abs()));
+            queue.insert_k(-3);
+            assert!(serde_json::to_string(&queue).is_err());
+        }
+
+        #[test]
+        fn tampered_heap_is_rebuilt() {
+            let json = r#"{"order":"Asc","max_len":null,"stable":false,"next_handle":4,"items":[
+                {"key":9,"value":null,"handle":0},
+                {"key":5,"value":"five","handle":3},
+                {"key":1,"value":null,"handle":2},
+                {"key":7,"value":null,"handle":1}
+            ]}"#;
+            let mut queue: PQueue<u8, String> = serde_json::from_str(json).unwrap();
+            assert_heap(&queue);
+            assert_eq!(queue.remove(Handle(3)), Ok((5, Some(String::from("five")))));
+            assert_eq!(queue.pop_k(), Some(1));
+            assert_eq!(queue.pop_k(), Some(7));
+            assert_eq!(queue.pop_k(), Some(9));
+        }
+
+        #[test]
+        fn invalid_data_is_rejected() {
+            let repeated = r#"{"order":"Asc","max_len":null,"stable":false,"next_handle":2,
+                "items":[{"key":1,"value":null,"handle":0},{"key":2,"value":null,"handle":0}]}"#;
+            assert!(serde_json::from_str::<PQueue<u8, ()>>(repeated).is_err());
+            let unknown_handle = r#"{"order":"Asc","max_len":null,"stable":false,"next_handle":1,
+                "items":[{"key":1,"value":null,"handle":5}]}"#;
+            assert!(serde_json::from_str::<PQueue<u8, ()>>(unknown_handle).is_err());
+            let too_long = r#"{"order":"Asc","max_len":1,"stable":false,"next_handle":2,
+                "items":[{"key":1,"value":null,"handle":0},{"key":2,"value":null,"handle":1}]}"#;
+            assert!(serde_json::from_str::<PQueue<u8, ()>>(too_long).is_err());
+            let unknown_order = r#"{"order":"Sideways","max_len":null,"stable":false,"next_handle":0,"items":[]}"#;
+            assert!(serde_json::from_str::<PQueue<u8, ()>>(unknown_order).is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[test]
+    fn length_of_the_queue() {
         let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
         assert_eq!(queue.len(), 0);
         queue.insert_k(0);
@@ -279,8 +1732,8 @@ mod tests {
         let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
         assert_eq!(queue.top_k(), None);
         queue.insert_kv(0, String::from("Value on key 0"));
-        assert_eq!(queue.top_k(), Some(0));
-        assert_eq!(queue.top_k(), Some(0));
+        assert_eq!(queue.top_k(), Some(&0));
+        assert_eq!(queue.top_k(), Some(&0));
     }
 
     #[test]
@@ -288,9 +1741,8 @@ mod tests {
         let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
         queue.insert_kv(0, String::from("Value on key 0"));
         match queue.top_kv() {
-            Some((0, Some(rc))) => {
-                assert_eq!(Rc::strong_count(&rc), 2);
-                assert_eq!((*rc).clone(), String::from("Value on key 0"));
+            Some((0, Some(value))) => {
+                assert_eq!(value, &String::from("Value on key 0"));
             },
             _ => assert!(false),
         };
@@ -304,7 +1756,7 @@ mod tests {
         let keys: Vec<u8> = Vec::from((0..10).collect::<Vec<u8>>());
         queue.bulk_insert_k(keys);
         assert_eq!(queue.len(), 10);
-        assert_eq!(queue.top_k(), Some(9));
+        assert_eq!(queue.top_k(), Some(&9));
         assert_eq!(queue.pop_k(), Some(9));
         assert_eq!(queue.pop_k(), Some(8));
         assert_eq!(queue.pop_k(), Some(7));
@@ -357,4 +1809,1406 @@ mod tests {
         assert_eq!(elements.len(), 10 - to_pop);
         assert_eq!(elements, vec![6, 5, 4, 3, 2, 1, 0]);
     }
+
+    pub(super) fn assert_heap<K: cmp::Ord, V, const D: usize>(queue: &PQueue<K, V, D>) {
+        for node_i in 2..=queue.vec.len() {
+            assert!(!queue.higher_priority(node_i, PQueue::<K, V, D>::father(node_i)));
+        }
+        assert_eq!(queue.positions.len(), queue.vec.len());
+        for (node_i, item) in queue.vec.iter().enumerate() {
+            assert_eq!(queue.positions[&item.handle], node_i + 1);
+            if let Some(key_index) = &queue.key_index {
+                let handles = key_index.handles(&item.key);
+                assert!(handles.contains(&item.handle));
+                assert_eq!(handles.len(), queue.vec.iter().filter(|other| other.key == item.key).count());
+            }
+        }
+        assert!(queue.expirations.keys().all(|handle| queue.positions.contains_key(handle)));
+        if let Some(lowest) = queue.lowest_priority_node() {
+            assert!((1..=queue.vec.len()).all(|node_i| !queue.higher_priority(lowest, node_i) || lowest == node_i));
+        }
+    }
+
+    #[test]
+    fn insert_returns_distinct_handles() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        let first = queue.insert_k(1);
+        let second = queue.insert_kv(1, String::from("Value on key 1"));
+        assert_ne!(first, second);
+        assert!(queue.contains(first));
+        assert!(queue.contains(second));
+        assert_heap(&queue);
+    }
+
+    #[test]
+    fn decrease_key() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..20).map(|n| queue.insert_k(n)).collect();
+        assert_eq!(queue.update_key(handles[15], -1), Ok(()));
+        assert_heap(&queue);
+        assert_eq!(queue.pop_k(), Some(-1));
+        assert_eq!(queue.pop_k(), Some(0));
+    }
+
+    #[test]
+    fn increase_key() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..20).map(|n| queue.insert_k(n)).collect();
+        assert_eq!(queue.update_key(handles[0], 100), Ok(()));
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(20), (1..20).chain([100]).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn update_key_keeps_value() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Desc);
+        queue.insert_k(5);
+        let handle = queue.insert_kv(1, String::from("Value on key 1"));
+        queue.update_key(handle, 10).unwrap();
+        assert_eq!(queue.pop_kv(), Some((10, Some(String::from("Value on key 1")))));
+    }
+
+    #[test]
+    fn update_key_of_popped_item() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        let handle = queue.insert_k(0);
+        queue.insert_k(1);
+        queue.pop_k();
+        assert!(!queue.contains(handle));
+        assert_eq!(queue.update_key(handle, 3), Err(PQueueError::InvalidHandle));
+        assert_heap(&queue);
+    }
+
+    #[test]
+    fn remove_by_handle() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..20).map(|n| queue.insert_kv(n, n.to_string())).collect();
+        assert_eq!(queue.remove(handles[7]), Ok((7, Some(String::from("7")))));
+        assert_heap(&queue);
+        assert_eq!(queue.remove(handles[0]), Ok((0, Some(String::from("0")))));
+        assert_heap(&queue);
+        assert_eq!(queue.remove(handles[19]), Ok((19, Some(String::from("19")))));
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 17);
+        let expected: Vec<i32> = (1..19).filter(|n| *n != 7).collect();
+        assert_eq!(queue.bulk_pop_k(20), expected);
+    }
+
+    #[test]
+    fn remove_moves_last_item_up() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        queue.bulk_insert_k(vec![0, 10, 1, 11, 12, 2, 3]);
+        let handle = queue.vec[4].handle;
+        assert_eq!(queue.remove(handle), Ok((12, None)));
+        assert_eq!(queue.vec[1].key, 3);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(10), vec![0, 1, 2, 3, 10, 11]);
+    }
+
+    #[test]
+    fn remove_with_stale_handle() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        let handle = queue.insert_k(3);
+        queue.insert_k(4);
+        assert_eq!(queue.remove(handle), Ok((3, None)));
+        assert_eq!(queue.remove(handle), Err(PQueueError::InvalidHandle));
+        assert_eq!(queue.update_key(handle, 1), Err(PQueueError::InvalidHandle));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn stable_queue_pops_equal_keys_in_insertion_order() {
+        let mut queue: PQueue<u8, usize> = PQueue::new(Priority::Asc).stable();
+        assert!(queue.is_stable());
+        for n in 0..30 {
+            queue.insert_kv((n % 3) as u8, n);
+        }
+        assert_heap(&queue);
+        let popped = queue.bulk_pop_kv(30);
+        let expected: Vec<(u8, Option<usize>)> = (0..3)
+            .flat_map(|key| (0..30).filter(move |n| n % 3 == key).map(move |n| (key as u8, Some(n))))
+            .collect();
+        assert_eq!(popped, expected);
+    }
+
+    #[test]
+    fn stable_descending_queue() {
+        let mut queue: PQueue<u8, char> = PQueue::new(Priority::Desc).stable();
+        queue.bulk_insert_kv(vec![(1, 'a'), (2, 'b'), (1, 'c'), (2, 'd'), (1, 'e')]);
+        let values: Vec<char> = queue.map(|(_, value)| value.unwrap()).collect();
+        assert_eq!(values, vec!['b', 'd', 'a', 'c', 'e']);
+    }
+
+    #[test]
+    fn make_filled_queue_stable() {
+        let mut queue: PQueue<u8, char> = PQueue::new(Priority::Asc);
+        queue.bulk_insert_kv(vec![(0, 'a'), (0, 'b'), (0, 'c'), (0, 'd'), (0, 'e'), (0, 'f')]);
+        queue.remove(queue.vec[0].handle).unwrap();
+        let mut queue = queue.stable();
+        assert_heap(&queue);
+        let values: Vec<char> = queue.bulk_pop_kv(6).into_iter().map(|(_, value)| value.unwrap()).collect();
+        assert_eq!(values, vec!['b', 'c', 'd', 'e', 'f']);
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let mut queue: PQueue<i32, String> = PQueue::with_comparator(|a: &i32, b: &i32| {
+            (a - 10).abs().cmp(&(b - 10).abs())
+        });
+        queue.bulk_insert_k(vec![0, 30, 12, 7, 10, -5, 15]);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(7), vec![10, 12, 7, 15, 0, -5, 30]);
+    }
+
+    #[test]
+    fn custom_comparator_with_stable_queue() {
+        let mut queue: PQueue<char, String> = PQueue::with_comparator(|a: &char, b: &char| {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }).stable();
+        for key in "bBaAcCAa".chars() {
+            queue.insert_k(key);
+        }
+        assert_eq!(queue.bulk_pop_k(8).into_iter().collect::<String>(), "aAAabBcC");
+    }
+
+    #[test]
+    fn custom_comparator_with_update_key() {
+        let mut queue: PQueue<i32, String> = PQueue::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        let handles: Vec<Handle> = (0..10).map(|n| queue.insert_k(n)).collect();
+        assert_eq!(queue.top_k(), Some(&9));
+        queue.update_key(handles[2], 20).unwrap();
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![20, 9, 8]);
+    }
+
+    #[test]
+    fn from_vec() {
+        let keys_values: Vec<(i32, Option<String>)> = (0..100)
+            .map(|n| ((n * 37) % 100, if n % 2 == 0 { Some(n.to_string()) } else { None }))
+            .collect();
+        let mut queue: PQueue<i32, String> = PQueue::from_vec(Priority::Desc, keys_values);
+        assert_eq!(queue.len(), 100);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(100), (0..100).rev().collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn from_empty_vec() {
+        let queue: PQueue<u8, String> = PQueue::from_vec(Priority::Asc, Vec::new());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn bulk_insert_rebuilds_heap() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        queue.insert_k(50);
+        queue.bulk_insert_k((0..1000).map(|n| (n * 7) % 1000).collect());
+        assert_heap(&queue);
+        queue.bulk_insert_k(vec![-1, 2000]);
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 1003);
+        assert_eq!(queue.pop_k(), Some(-1));
+        assert_eq!(queue.pop_k(), Some(0));
+    }
+
+    #[test]
+    fn bulk_insert_keeps_insertion_order_in_stable_queue() {
+        let mut queue: PQueue<u8, usize> = PQueue::new(Priority::Asc).stable();
+        queue.bulk_insert_kv((0..100).map(|n| ((n % 2) as u8, n)).collect());
+        assert_heap(&queue);
+        let values: Vec<usize> = queue.bulk_pop_kv(50).into_iter().map(|(_, value)| value.unwrap()).collect();
+        assert_eq!(values, (0..100).step_by(2).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn append_small_queue() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..100).map(|n| queue.insert_k(n * 2)).collect();
+        let mut other = PQueue::new(Priority::Asc);
+        let other_handle = other.insert_kv(5, String::from("Value on key 5"));
+        other.insert_k(-1);
+        assert_eq!(queue.append(&mut other), Ok(()));
+        assert!(other.is_empty());
+        assert!(!other.contains(other_handle));
+        assert_eq!(queue.len(), 102);
+        assert_heap(&queue);
+        assert_eq!(queue.remove(handles[3]), Ok((6, None)));
+        assert_eq!(queue.bulk_pop_kv(5), vec![
+            (-1, None), (0, None), (2, None), (4, None), (5, Some(String::from("Value on key 5")))
+        ]);
+    }
+
+    #[test]
+    fn append_large_queue() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Desc);
+        let handle = queue.insert_k(50);
+        let mut other = PQueue::new(Priority::Desc);
+        other.bulk_insert_k((0..100).collect());
+        queue.append(&mut other).unwrap();
+        assert_heap(&queue);
+        queue.update_key(handle, 1000).unwrap();
+        assert_eq!(queue.bulk_pop_k(3), vec![1000, 99, 98]);
+        assert_eq!(queue.len(), 98);
+    }
+
+    #[test]
+    fn append_queue_with_different_priority() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let mut other = PQueue::new(Priority::Desc);
+        other.insert_k(1);
+        assert_eq!(queue.append(&mut other), Err(PQueueError::PriorityMismatch));
+        assert_eq!(other.len(), 1);
+        let mut custom = PQueue::with_comparator(|a: &i32, b: &i32| a.cmp(b));
+        assert_eq!(queue.append(&mut custom), Err(PQueueError::PriorityMismatch));
+        let mut other_custom = PQueue::with_comparator(|a: &i32, b: &i32| a.cmp(b));
+        assert_eq!(custom.append(&mut other_custom), Err(PQueueError::PriorityMismatch));
+        let mut like_custom = custom.new_like();
+        assert_eq!(custom.append(&mut like_custom), Ok(()));
+    }
+
+    #[test]
+    fn append_keeps_insertion_order_in_stable_queue() {
+        let mut queue: PQueue<u8, char> = PQueue::new(Priority::Asc).stable();
+        let mut other = queue.new_like();
+        other.bulk_insert_kv(vec![(0, 'c'), (1, 'x'), (0, 'd'), (0, 'e')]);
+        queue.bulk_insert_kv(vec![(0, 'a'), (0, 'b')]);
+        queue.append(&mut other).unwrap();
+        let values: Vec<char> = queue.map(|(_, value)| value.unwrap()).collect();
+        assert_eq!(values, vec!['a', 'b', 'c', 'd', 'e', 'x']);
+    }
+
+    #[test]
+    fn merge_queues() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        queue.bulk_insert_k(vec![4, 2, 0]);
+        let mut other = PQueue::new(Priority::Asc);
+        other.bulk_insert_k(vec![5, 3, 1]);
+        let mut merged = queue.merge(other).unwrap();
+        assert_eq!(merged.priority(), Some(Priority::Asc));
+        assert_eq!(merged.bulk_pop_k(10), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    fn check_arity<const D: usize>() {
+        let mut queue: PQueue<i32, String, D> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..200).map(|n| queue.insert_k((n * 71) % 200)).collect();
+        assert_heap(&queue);
+        queue.update_key(handles[10], -1).unwrap();
+        queue.update_key(handles[0], 500).unwrap();
+        queue.remove(handles[50]).unwrap();
+        assert_heap(&queue);
+        let changed = [0, (10 * 71) % 200, (50 * 71) % 200];
+        let mut expected: Vec<i32> = (0..200).filter(|n| !changed.contains(n)).collect();
+        expected.insert(0, -1);
+        expected.push(500);
+        assert_eq!(queue.bulk_pop_k(200), expected);
+        queue.bulk_insert_k((0..100).rev().collect());
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn heap_with_different_arities() {
+        check_arity::<2>();
+        check_arity::<3>();
+        check_arity::<4>();
+        check_arity::<8>();
+    }
+
+    #[test]
+    fn debug_print_quaternary_heap() {
+        let mut queue: PQueue<u8, String, 4> = PQueue::new(Priority::Asc);
+        queue.bulk_insert_k((0..6).collect());
+        assert_eq!(format!("{:?}", queue), [
+            " - 0, pos: 1",
+            "     - 1, pos: 2",
+            "         - 5, pos: 6",
+            "     - 2, pos: 3",
+            "     - 3, pos: 4",
+            "     - 4, pos: 5",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn debug_print_binary_heap() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        assert_eq!(format!("{:?}", queue), "empty queue");
+        queue.bulk_insert_k((0..4).collect());
+        assert_eq!(format!("{:?}", queue), [
+            " - 0, pos: 1",
+            "     - 1, pos: 2",
+            "         - 3, pos: 4",
+            "     - 2, pos: 3",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn bounded_queue_keeps_highest_priorities() {
+        let mut queue: PQueue<i32, String> = PQueue::with_max_len(5, Priority::Desc);
+        assert_eq!(queue.max_len(), Some(5));
+        for n in 0..100 {
+            queue.insert_k((n * 37) % 100);
+            assert!(queue.len() <= 5);
+            assert_heap(&queue);
+        }
+        assert_eq!(queue.bulk_pop_k(10), vec![99, 98, 97, 96, 95]);
+    }
+
+    #[test]
+    fn offer_to_bounded_queue() {
+        let mut queue: PQueue<i32, String> = PQueue::with_max_len(3, Priority::Asc);
+        assert!(matches!(queue.offer_kv(5, String::from("five")), Offer::Inserted(_)));
+        assert!(matches!(queue.offer_k(3), Offer::Inserted(_)));
+        assert!(matches!(queue.offer_k(4), Offer::Inserted(_)));
+        assert_eq!(queue.offer_k(6), Offer::Rejected((6, None)));
+        assert_eq!(queue.offer_k(5), Offer::Rejected((5, None)));
+        match queue.offer_kv(1, String::from("one")) {
+            Offer::Evicted(handle, evicted) => {
+                assert!(queue.contains(handle));
+                assert_eq!(evicted, (5, Some(String::from("five"))));
+            },
+            offer => panic!("the key 5 should be evicted, got {:?}", offer),
+        }
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(3), vec![(1, Some(String::from("one"))), (3, None), (4, None)]);
+    }
+
+    #[test]
+    fn insert_rejected_by_bounded_queue() {
+        let mut queue: PQueue<i32, String> = PQueue::with_max_len(1, Priority::Asc);
+        let handle = queue.insert_k(1);
+        let rejected = queue.insert_k(2);
+        assert!(queue.contains(handle));
+        assert!(!queue.contains(rejected));
+        assert_ne!(handle, rejected);
+        let mut empty: PQueue<i32, String> = PQueue::with_max_len(0, Priority::Asc);
+        assert_eq!(empty.offer_k(1), Offer::Rejected((1, None)));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn bounded_stable_queue_rejects_newer_equal_keys() {
+        let mut queue: PQueue<u8, char> = PQueue::with_max_len(2, Priority::Asc).stable();
+        queue.insert_kv(0, 'a');
+        queue.insert_kv(1, 'b');
+        assert_eq!(queue.offer_kv(1, 'c'), Offer::Rejected((1, Some('c'))));
+        assert!(matches!(queue.offer_kv(0, 'd'), Offer::Evicted(_, (1, Some('b')))));
+    }
+
+    #[test]
+    fn bulk_insert_in_bounded_queue() {
+        let mut queue: PQueue<i32, String> = PQueue::with_max_len(10, Priority::Asc);
+        let handle = queue.insert_k(3);
+        queue.bulk_insert_k((0..1000).rev().collect());
+        assert_eq!(queue.len(), 10);
+        // the items are bounded while they are inserted, instead of truncating the whole input
+        assert!(queue.vec.capacity() < 100);
+        assert_heap(&queue);
+        assert!(queue.contains(handle));
+        let mut other = queue.new_like();
+        other.bulk_insert_k(vec![-3, -2, -1]);
+        queue.append(&mut other).unwrap();
+        assert_eq!(queue.len(), 10);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(10), vec![-3, -2, -1, 0, 1, 2, 3, 3, 4, 5]);
+    }
+
+    #[test]
+    fn bounded_queue_tracks_lowest_item() {
+        let mut queue: PQueue<u32, u32, 3> = PQueue::with_max_len(20, Priority::Desc);
+        let mut handles = Vec::new();
+        for n in 0..200u32 {
+            handles.push(queue.insert_kv(n.wrapping_mul(2654435761) % 1000, n));
+            assert_heap(&queue);
+        }
+        let alive: Vec<Handle> = handles.into_iter().filter(|handle| queue.contains(*handle)).collect();
+        assert_eq!(alive.len(), 20);
+        queue.update_key(alive[0], 0).unwrap();
+        assert_heap(&queue);
+        queue.update_key(alive[1], 5000).unwrap();
+        assert_heap(&queue);
+        queue.remove(alive[2]).unwrap();
+        assert_heap(&queue);
+        *queue.top_mut().unwrap().key_mut() = 1;
+        assert_heap(&queue);
+        queue.replace_top_kv(3, 0);
+        assert_heap(&queue);
+        queue.retain(|key, _| key % 3 != 0);
+        assert_heap(&queue);
+        queue.pop_k();
+        assert_heap(&queue);
+        let lowest = *queue.to_sorted_vec().last().map(|(key, _)| key).unwrap();
+        let len = queue.len();
+        while queue.len() < 20 {
+            queue.insert_k(lowest + 1);
+        }
+        assert!(matches!(queue.offer_k(lowest + 2), Offer::Evicted(_, (key, _)) if key == lowest));
+        assert_eq!(queue.offer_k(0), Offer::Rejected((0, None)));
+        assert_eq!(queue.len(), 20);
+        assert!(len < 20);
+        assert_heap(&queue);
+    }
+
+    #[test]
+    fn iterate_without_popping() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        queue.insert_kv(2, String::from("two"));
+        queue.bulk_insert_k(vec![3, 0, 1]);
+        let mut items: Vec<(&i32, Option<&String>)> = queue.iter().collect();
+        assert_eq!(items[0], (&0, None));
+        items.sort();
+        assert_eq!(items, vec![(&0, None), (&1, None), (&2, Some(&String::from("two"))), (&3, None)]);
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn into_sorted_vec() {
+        let mut queue: PQueue<i32, i32> = PQueue::new(Priority::Desc);
+        queue.bulk_insert_kv((0..50).map(|n| ((n * 7) % 50, -n)).collect());
+        let sorted = queue.into_sorted_vec();
+        assert_eq!(sorted.iter().map(|(key, _)| *key).collect::<Vec<i32>>(), (0..50).rev().collect::<Vec<i32>>());
+        assert!(sorted.iter().all(|(key, value)| (-value.unwrap() * 7) % 50 == *key));
+        let empty: PQueue<i32, i32> = PQueue::new(Priority::Desc);
+        assert_eq!(empty.into_sorted_vec(), vec![]);
+    }
+
+    #[test]
+    fn into_sorted_vec_of_stable_quaternary_heap() {
+        let mut queue: PQueue<u8, usize, 4> = PQueue::new(Priority::Asc).stable();
+        queue.bulk_insert_kv((0..40).map(|n| ((n % 4) as u8, n)).collect());
+        let values: Vec<usize> = queue.into_sorted_vec().into_iter().map(|(_, value)| value.unwrap()).collect();
+        let expected: Vec<usize> = (0..4).flat_map(|key| (key..40).step_by(4)).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn to_sorted_vec_shares_rc_values() {
+        let mut queue: PQueue<i32, Rc<String>> = PQueue::new(Priority::Asc);
+        queue.insert_kv(1, Rc::new(String::from("one")));
+        queue.insert_k(0);
+        let sorted = queue.to_sorted_vec();
+        assert_eq!(sorted.len(), 2);
+        assert_eq!(sorted[0], (0, None));
+        match &sorted[1] {
+            (1, Some(rc)) => {
+                assert_eq!(Rc::strong_count(rc), 2);
+                assert_eq!(**rc, String::from("one"));
+            },
+            pair => panic!("unexpected pair {:?}", pair),
+        }
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn retain_items() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..100).map(|n| queue.insert_kv(n, (n % 3).to_string())).collect();
+        queue.retain(|key, value| *key < 50 && value != Some(&String::from("0")));
+        assert_heap(&queue);
+        assert!(!queue.contains(handles[0]));
+        assert!(queue.contains(handles[1]));
+        assert!(!queue.contains(handles[70]));
+        queue.update_key(handles[49], -1).unwrap();
+        let mut expected: Vec<i32> = (0..49).filter(|n| n % 3 != 0).collect();
+        expected.insert(0, -1);
+        assert_eq!(queue.bulk_pop_k(100), expected);
+    }
+
+    #[test]
+    fn drain_filter_items() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Desc);
+        queue.bulk_insert_kv((0..20).map(|n| (n, n.to_string())).collect());
+        let mut removed = queue.drain_filter(|key, _| key % 2 == 1);
+        removed.sort();
+        assert_eq!(removed, (0..20).filter(|n| n % 2 == 1).map(|n| (n, Some(n.to_string()))).collect::<Vec<_>>());
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 10);
+        assert_eq!(queue.drain_filter(|_, _| false), vec![]);
+        assert_eq!(queue.pop_k(), Some(18));
+    }
+
+    #[test]
+    fn drain_and_clear() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        let handle = queue.insert_k(3);
+        queue.bulk_insert_k(vec![1, 2]);
+        let mut drained: Vec<i32> = queue.drain().map(|(key, _)| key).collect();
+        drained.sort();
+        assert_eq!(drained, vec![1, 2, 3]);
+        assert!(queue.is_empty());
+        assert!(!queue.contains(handle));
+        queue.bulk_insert_k(vec![1, 2]);
+        drop(queue.drain());
+        assert!(queue.is_empty());
+        let handle = queue.insert_k(5);
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.remove(handle), Err(PQueueError::InvalidHandle));
+        assert_heap(&queue);
+    }
+
+    // A clock that only moves when the test sets the returned time.
+    fn manual_clock() -> (Arc<Mutex<Instant>>, impl Fn() -> Instant + Send + Sync) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = {
+            let now = Arc::clone(&now);
+            move || *now.lock().unwrap()
+        };
+        (now, clock)
+    }
+
+    #[test]
+    fn pop_discards_expired_items() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).clock(clock).on_expired({
+            let dropped = Arc::clone(&dropped);
+            move |key, value| dropped.lock().unwrap().push((key, value))
+        });
+        let start = *now.lock().unwrap();
+        let soon = start + Duration::from_secs(10);
+        let later = start + Duration::from_secs(3600);
+        queue.insert_kv_expiring(1, "expired", soon);
+        let handle = queue.insert_kv_expiring(2, "alive", later);
+        queue.insert_k_expiring(3, soon);
+        queue.insert_kv(4, "forever");
+        assert_eq!(queue.expires_at(handle), Some(later));
+        assert_eq!(queue.top_k(), Some(&1));
+        *now.lock().unwrap() = soon;
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.top_kv(), Some((&2, Some(&"alive"))));
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.pop_kv(), Some((2, Some("alive"))));
+        assert_eq!(queue.expires_at(handle), None);
+        assert_eq!(queue.pop_k(), Some(4));
+        assert_eq!(queue.pop_kv(), None);
+        assert_eq!(*dropped.lock().unwrap(), vec![(1, Some("expired")), (3, None)]);
+        assert_heap(&queue);
+    }
+
+    #[test]
+    fn top_skips_expired_subtrees() {
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u32, (), 3> = PQueue::new(Priority::Desc).clock(clock);
+        let deadline = *now.lock().unwrap() + Duration::from_millis(1);
+        for key in 0..50 {
+            if key % 7 == 3 {
+                queue.insert_k(key);
+            } else {
+                queue.insert_k_expiring(key, deadline);
+            }
+        }
+        assert_eq!(queue.top_k(), Some(&49));
+        *now.lock().unwrap() = deadline;
+        assert_eq!(queue.top_k(), Some(&45));
+        assert_eq!(queue.pop_k(), Some(45));
+        assert_eq!(queue.top_k(), Some(&38));
+        assert_eq!(queue.len(), 45);
+        assert_heap(&queue);
+    }
+
+    // A queue whose top and the item after it have expired, with the keys passed to the callback of `on_expired`.
+    fn queue_with_expired_top() -> (Arc<Mutex<Vec<u8>>>, PQueue<u8, ()>) {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let (now, clock) = manual_clock();
+        let mut queue = PQueue::new(Priority::Asc).clock(clock).on_expired({
+            let dropped = Arc::clone(&dropped);
+            move |key, _| dropped.lock().unwrap().push(key)
+        });
+        let deadline = *now.lock().unwrap() + Duration::from_secs(1);
+        queue.insert_k_expiring(1, deadline);
+        queue.insert_k_expiring(2, deadline);
+        queue.insert_k(5);
+        queue.insert_k(7);
+        *now.lock().unwrap() = deadline;
+        (dropped, queue)
+    }
+
+    #[test]
+    fn top_mut_discards_expired_items() {
+        let (dropped, mut queue) = queue_with_expired_top();
+        *queue.top_mut().unwrap().key_mut() = 9;
+        assert_eq!(*dropped.lock().unwrap(), vec![1, 2]);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![7, 9]);
+        assert!(queue.top_mut().is_none());
+    }
+
+    #[test]
+    fn push_pop_discards_expired_items() {
+        let (dropped, mut queue) = queue_with_expired_top();
+        assert_eq!(queue.push_pop_k(6), (5, None));
+        assert_eq!(*dropped.lock().unwrap(), vec![1, 2]);
+        assert_eq!(queue.push_pop_k(3), (3, None));
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![6, 7]);
+    }
+
+    #[test]
+    fn replace_top_discards_expired_items() {
+        let (dropped, mut queue) = queue_with_expired_top();
+        assert_eq!(queue.replace_top_k(8), Some((5, None)));
+        assert_eq!(*dropped.lock().unwrap(), vec![1, 2]);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![7, 8]);
+
+        let (dropped, mut queue) = queue_with_expired_top();
+        queue.retain(|key, _| *key < 5);
+        assert_eq!(queue.replace_top_k(3), None);
+        assert_eq!(*dropped.lock().unwrap(), vec![1, 2]);
+        assert_eq!(queue.bulk_pop_k(2), vec![3]);
+    }
+
+    #[test]
+    fn expired_items_dont_keep_unique_keys() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).clock(clock).on_expired({
+            let dropped = Arc::clone(&dropped);
+            move |key, value| dropped.lock().unwrap().push((key, value))
+        });
+        queue = queue.unique(DuplicatePolicy::Reject);
+        let deadline = *now.lock().unwrap() + Duration::from_secs(1);
+        queue.insert_kv_expiring(1, "old", deadline);
+        queue.insert_kv_expiring(4, "old", deadline);
+        queue.insert_kv_expiring(6, "old", deadline);
+        queue.insert_kv(5, "kept");
+        assert!(matches!(queue.offer_kv(1, "new"), Offer::Rejected(_)));
+        *now.lock().unwrap() = deadline;
+        assert!(matches!(queue.offer_kv(1, "new"), Offer::Inserted(_)));
+        assert_eq!(queue.count_key(&1), 1);
+        assert_eq!(*dropped.lock().unwrap(), vec![(1, Some("old"))]);
+        assert_eq!(queue.push_pop_kv(6, "new"), (1, Some("new")));
+        assert_eq!(queue.replace_top_kv(4, "new"), Some((5, Some("kept"))));
+        assert_eq!(*dropped.lock().unwrap(), vec![(1, Some("old")), (6, Some("old")), (4, Some("old"))]);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(3), vec![(4, Some("new")), (6, Some("new"))]);
+    }
+
+    #[test]
+    fn expired_items_dont_keep_keys_set_later() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc).clock(clock).on_expired({
+            let dropped = Arc::clone(&dropped);
+            move |key, _| dropped.lock().unwrap().push(key)
+        });
+        queue = queue.unique(DuplicatePolicy::Reject);
+        let deadline = *now.lock().unwrap() + Duration::from_secs(1);
+        queue.insert_k(1);
+        for key in [2, 5, 8] {
+            queue.insert_k_expiring(key, deadline);
+        }
+        let handle = queue.insert_k(3);
+        queue.bulk_insert_k(vec![4, 6, 7]);
+        assert_eq!(queue.top_mut().unwrap().set_key(5), Err(PQueueError::DuplicateKey));
+        *now.lock().unwrap() = deadline;
+        {
+            // the top was already modified when the expired item is removed
+            let mut top = queue.top_mut().unwrap();
+            assert_eq!(top.set_key(9), Ok(1));
+            assert_eq!(top.set_key(2), Ok(9));
+        }
+        assert_heap(&queue);
+        assert_eq!(queue.top_mut().unwrap().set_key(5), Ok(2));
+        assert_eq!(queue.update_key(handle, 8), Ok(()));
+        assert_eq!(*dropped.lock().unwrap(), vec![2, 5, 8]);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(6), vec![4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn lookups_skip_expired_items() {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let start = Instant::now();
+        for indexed in [false, true] {
+            let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).clock(move || start).on_expired({
+                let dropped = Arc::clone(&dropped);
+                move |key, value| dropped.lock().unwrap().push((key, value))
+            });
+            if indexed {
+                queue = queue.indexed();
+            }
+            queue.insert_kv_expiring(1, "expired", start);
+            queue.insert_kv(1, "one");
+            queue.insert_kv_expiring(2, "expired", start);
+            queue.insert_kv_expiring(3, "later", start + Duration::from_secs(10));
+            assert!(!queue.contains_key(&2));
+            assert!(queue.contains_key(&3));
+            assert_eq!(queue.count_key(&1), 1);
+            assert_eq!(queue.get(&1), vec![Some(&"one")]);
+            assert_eq!(queue.get(&2), vec![]);
+            assert_eq!(queue.to_sorted_vec(), vec![(1, Some("one")), (3, Some("later"))]);
+            assert_eq!(queue.len(), 4);
+            assert!(dropped.lock().unwrap().is_empty());
+            assert_eq!(queue.into_sorted_vec(), vec![(1, Some("one")), (3, Some("later"))]);
+            let mut discarded = mem::take(&mut *dropped.lock().unwrap());
+            discarded.sort();
+            assert_eq!(discarded, vec![(1, Some("expired")), (2, Some("expired"))]);
+        }
+    }
+
+    #[test]
+    fn unique_keeps_expirations() {
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).clock(clock);
+        let start = *now.lock().unwrap();
+        queue.insert_kv_expiring(1, "expired", start);
+        queue.insert_kv(2, "first");
+        let handle = queue.insert_kv_expiring(2, "second", start + Duration::from_secs(10));
+        queue.insert_kv(3, "forever");
+        let mut queue = queue.unique(DuplicatePolicy::Replace);
+        assert_eq!(queue.expires_at(handle), Some(start + Duration::from_secs(10)));
+        assert_heap(&queue);
+        assert_eq!(queue.pop_kv(), Some((2, Some("second"))));
+        assert_eq!(queue.pop_kv(), Some((3, Some("forever"))));
+        assert_eq!(queue.pop_kv(), None);
+    }
+
+    #[test]
+    fn purge_expired_items() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc).stable();
+        let start = Instant::now();
+        let handles: Vec<Handle> = (0..20)
+            .map(|n| queue.insert_kv_expiring(n % 5, n.to_string(), start + Duration::from_secs(n as u64)))
+            .collect();
+        queue.insert_kv(0, String::from("forever"));
+        assert_eq!(queue.purge_expired(start - Duration::from_secs(1)), vec![]);
+        let mut purged = queue.purge_expired(start + Duration::from_secs(9));
+        purged.sort();
+        let mut expected: Vec<_> = (0..10).map(|n| (n % 5, Some(n.to_string()))).collect();
+        expected.sort();
+        assert_eq!(purged, expected);
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 11);
+        assert!(!queue.contains(handles[9]));
+        assert_eq!(queue.expires_at(handles[10]), Some(start + Duration::from_secs(10)));
+        assert_eq!(queue.pop_kv(), Some((0, Some(String::from("10")))));
+        queue.clear();
+        assert_eq!(queue.expires_at(handles[11]), None);
+    }
+
+    #[test]
+    fn append_keeps_expirations() {
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc).clock(clock);
+        let mut other = queue.new_like();
+        let start = *now.lock().unwrap();
+        queue.insert_k(5);
+        other.insert_k_expiring(1, start);
+        other.insert_k_expiring(2, start + Duration::from_secs(3600));
+        queue.append(&mut other).unwrap();
+        assert_heap(&queue);
+        assert_eq!(queue.pop_k(), Some(2));
+        assert_eq!(queue.pop_k(), Some(5));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn string_keys() {
+        let mut queue: PQueue<String, u8> = PQueue::new(Priority::Asc);
+        queue.insert_kv(String::from("pear"), 1);
+        let handle = queue.insert_k(String::from("banana"));
+        queue.insert_k(String::from("apple"));
+        assert_eq!(queue.top_k(), Some(&String::from("apple")));
+        queue.update_key(handle, String::from("zucchini")).unwrap();
+        assert_eq!(queue.to_sorted_vec().into_iter().map(|(key, _)| key).collect::<Vec<String>>(), vec![
+            String::from("apple"), String::from("pear"), String::from("zucchini")
+        ]);
+        assert_eq!(queue.pop_k(), Some(String::from("apple")));
+        assert_eq!(queue.pop_kv(), Some((String::from("pear"), Some(1))));
+    }
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    struct Job {
+        priority: u8,
+        name: Vec<u8>,
+    }
+
+    impl SnapshotCodec for Job {
+        fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            self.priority.encode(writer)?;
+            self.name.encode(writer)
+        }
+
+        fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+            Ok(Job { priority: u8::decode(reader)?, name: Vec::decode(reader)? })
+        }
+    }
+
+    #[test]
+    fn keys_without_copy_and_display() {
+        let mut queue: PQueue<Job, String> = PQueue::new(Priority::Desc);
+        queue.insert_k(Job { priority: 1, name: b"backup".to_vec() });
+        queue.insert_k(Job { priority: 3, name: b"deploy".to_vec() });
+        queue.insert_kv(Job { priority: 2, name: b"build".to_vec() }, String::from("main"));
+        assert_eq!(queue.top_k().map(|job| job.priority), Some(3));
+        let names: Vec<Vec<u8>> = queue.map(|(job, _)| job.name).collect();
+        assert_eq!(names, vec![b"deploy".to_vec(), b"build".to_vec(), b"backup".to_vec()]);
+    }
+
+    #[test]
+    fn tuple_keys() {
+        let mut queue: PQueue<(u8, String), String> = PQueue::with_comparator(|a: &(u8, String), b: &(u8, String)| {
+            b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1))
+        });
+        queue.insert_k((1, String::from("b")));
+        queue.insert_k((2, String::from("z")));
+        queue.insert_k((1, String::from("a")));
+        assert_eq!(queue.top_kv().map(|(key, _)| key.clone()), Some((2, String::from("z"))));
+        assert_eq!(queue.bulk_pop_k(3), vec![
+            (2, String::from("z")), (1, String::from("a")), (1, String::from("b"))
+        ]);
+    }
+
+    #[test]
+    fn pop_value_shared_by_top() {
+        let mut queue: PQueue<u8, Rc<String>> = PQueue::new(Priority::Asc);
+        queue.insert_kv(0, Rc::new(String::from("Value on key 0")));
+        queue.insert_kv(1, Rc::new(String::from("Value on key 1")));
+        let top_value = queue.top_kv().and_then(|(_, value)| value.cloned()).unwrap();
+        let popped_value = queue.pop_kv().and_then(|(_, value)| value).unwrap();
+        assert!(Rc::ptr_eq(&top_value, &popped_value));
+        assert_eq!(*popped_value, String::from("Value on key 0"));
+    }
+
+    #[test]
+    fn values_without_clone() {
+        struct Connection {
+            id: u32,
+        }
+        let mut queue: PQueue<u8, Connection> = PQueue::new(Priority::Desc);
+        queue.bulk_insert_kv((0..10).map(|n| (n, Connection { id: n as u32 * 10 })).collect());
+        assert_eq!(queue.top_kv().and_then(|(_, value)| value).map(|connection| connection.id), Some(90));
+        let ids: Vec<u32> = queue.bulk_pop_kv(3).into_iter().map(|(_, value)| value.unwrap().id).collect();
+        assert_eq!(ids, vec![90, 80, 70]);
+    }
+
+    #[test]
+    fn remove_and_drain_after_to_sorted_vec() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        let handle = queue.insert_kv(0, String::from("zero"));
+        queue.insert_kv(1, String::from("one"));
+        queue.insert_kv(2, String::from("two"));
+        let sorted = queue.to_sorted_vec();
+        assert_eq!(queue.remove(handle), Ok((0, Some(String::from("zero")))));
+        let mut drained: Vec<(u8, Option<String>)> = queue.drain().collect();
+        drained.sort();
+        assert_eq!(drained, vec![(1, Some(String::from("one"))), (2, Some(String::from("two")))]);
+        assert_eq!(sorted.len(), 3);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut queue: PQueue<u32, String> = PQueue::with_max_len(50, Priority::Desc).stable();
+        let handles: Vec<Handle> = (0..40).map(|n| queue.insert_kv((n * 17) % 13, n.to_string())).collect();
+        queue.insert_k(100);
+        queue.remove(handles[3]).unwrap();
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], b"EPQS");
+
+        let mut restored: PQueue<u32, String> = PQueue::read_snapshot(bytes.as_slice()).unwrap();
+        assert_heap(&restored);
+        assert_eq!(restored.priority(), Some(Priority::Desc));
+        assert_eq!(restored.max_len(), Some(50));
+        assert!(restored.is_stable());
+        assert!(restored.iter().eq(queue.iter()));
+        assert!(!restored.contains(handles[3]));
+        assert_eq!(restored.remove(handles[5]), queue.remove(handles[5]));
+        let new_handle = restored.insert_k(7);
+        assert!(!handles.contains(&new_handle));
+        queue.insert_k(7);
+        assert_eq!(restored.into_sorted_vec(), queue.into_sorted_vec());
+    }
+
+    #[test]
+    fn snapshot_of_keys_without_hash() {
+        let mut queue: PQueue<Job, ()> = PQueue::new(Priority::Desc);
+        queue.insert_k(Job { priority: 1, name: b"backup".to_vec() });
+        queue.insert_k(Job { priority: 3, name: b"deploy".to_vec() });
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        let restored: PQueue<Job, ()> = PQueue::read_snapshot(bytes.as_slice()).unwrap();
+        let names: Vec<Vec<u8>> = restored.map(|(job, _)| job.name).collect();
+        assert_eq!(names, vec![b"deploy".to_vec(), b"backup".to_vec()]);
+    }
+
+    #[test]
+    fn snapshot_of_indexed_queue() {
+        let mut queue: PQueue<u32, String> = PQueue::new(Priority::Asc).indexed();
+        queue.bulk_insert_kv(vec![(3, String::from("a")), (1, String::from("b")), (3, String::from("c"))]);
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        let not_indexed = PQueue::<u32, String>::read_snapshot(bytes.as_slice()).map(|_| ()).unwrap_err();
+        assert_eq!(not_indexed.kind(), io::ErrorKind::InvalidData);
+        let mut restored: PQueue<u32, String> = PQueue::read_indexed_snapshot(bytes.as_slice()).unwrap();
+        assert!(restored.is_indexed());
+        assert_heap(&restored);
+        assert_eq!(restored.count_key(&3), 2);
+        assert_eq!(restored.remove_key(&1), Some((1, Some(String::from("b")))));
+        assert!(!restored.contains_key(&1));
+    }
+
+    #[test]
+    fn snapshot_of_queue_with_unique_keys() {
+        let mut queue: PQueue<u32, String> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::KeepBetter);
+        queue.bulk_insert_kv(vec![(3, String::from("a")), (1, String::from("b"))]);
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        assert!(PQueue::<u32, String>::read_snapshot(bytes.as_slice()).is_err());
+        let mut restored: PQueue<u32, String> = PQueue::read_indexed_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(restored.duplicate_policy(), Some(DuplicatePolicy::KeepBetter));
+        assert!(restored.is_indexed());
+        assert!(matches!(restored.offer_kv(1, String::from("c")), Offer::Rejected(_)));
+        assert_eq!(restored.count_key(&1), 1);
+        assert_heap(&restored);
+    }
+
+    #[test]
+    fn snapshot_keeps_time_left_to_expire() {
+        let (now, clock) = manual_clock();
+        let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc).clock(clock);
+        let start = *now.lock().unwrap();
+        queue.insert_k_expiring(1, start);
+        let expiring = queue.insert_k_expiring(2, start + Duration::from_secs(10));
+        let forever = queue.insert_k(3);
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+
+        // the time left is counted from the time of the clock set after reading the snapshot
+        let (now, clock) = manual_clock();
+        let later = *now.lock().unwrap() + Duration::from_secs(3600);
+        *now.lock().unwrap() = later;
+        let mut restored: PQueue<u8, ()> = PQueue::read_snapshot(bytes.as_slice()).unwrap();
+        restored = restored.clock(clock);
+        let expires_at = restored.expires_at(expiring).unwrap();
+        assert!(expires_at > later + Duration::from_secs(9) && expires_at <= later + Duration::from_secs(10));
+        assert_eq!(restored.expires_at(forever), None);
+        assert_eq!(restored.pop_k(), Some(2));
+        *now.lock().unwrap() = expires_at;
+        restored.insert_k_expiring(0, expires_at);
+        assert_eq!(restored.bulk_pop_k(3), vec![3]);
+    }
+
+    #[test]
+    fn read_first_snapshot_version() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Desc);
+        queue.bulk_insert_k(vec![2, 9, 4]);
+        // the first version had no options after max_len and no expiry after the values
+        let mut first_version = Vec::new();
+        let mut writer = ChecksumWriter::new(&mut first_version);
+        writer.write_all(&snapshot::MAGIC).unwrap();
+        1u8.encode(&mut writer).unwrap();
+        Priority::Desc.encode(&mut writer).unwrap();
+        false.encode(&mut writer).unwrap();
+        None::<usize>.encode(&mut writer).unwrap();
+        2usize.encode(&mut writer).unwrap();
+        queue.next_handle.encode(&mut writer).unwrap();
+        queue.vec.len().encode(&mut writer).unwrap();
+        for item in queue.vec.iter() {
+            item.handle.0.encode(&mut writer).unwrap();
+            item.key.encode(&mut writer).unwrap();
+            item.value.encode(&mut writer).unwrap();
+        }
+        writer.finish().unwrap();
+        let restored: PQueue<u8, String> = PQueue::read_snapshot(first_version.as_slice()).unwrap();
+        assert!(!restored.is_indexed());
+        assert_eq!(restored.positions, queue.positions);
+        assert_eq!(restored.into_sorted_vec(), queue.into_sorted_vec());
+    }
+
+    #[test]
+    fn snapshot_with_different_arity() {
+        let mut queue: PQueue<i64, ()> = PQueue::new(Priority::Asc);
+        queue.bulk_insert_k((0..100).map(|n| (n * 37) % 100 - 50).collect());
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        let mut restored: PQueue<i64, (), 4> = PQueue::read_snapshot(bytes.as_slice()).unwrap();
+        assert_heap(&restored);
+        assert_eq!(restored.bulk_pop_k(100), (-50..50).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        queue.insert_kv(1, String::from("one"));
+        queue.insert_k(2);
+        let mut bytes = Vec::new();
+        queue.write_snapshot(&mut bytes).unwrap();
+        let read = |bytes: &[u8]| PQueue::<u8, String>::read_snapshot(bytes).map(|_| ()).unwrap_err().kind();
+
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() - 6] ^= 0xff;
+        assert_eq!(read(&corrupted), io::ErrorKind::InvalidData);
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert_eq!(read(&wrong_magic), io::ErrorKind::InvalidData);
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert_eq!(read(&wrong_version), io::ErrorKind::InvalidData);
+        assert_eq!(read(&bytes[..bytes.len() - 1]), io::ErrorKind::UnexpectedEof);
+
+        let custom: PQueue<u8, ()> = PQueue::with_comparator(|a: &u8, b: &u8| b.cmp(a));
+        assert_eq!(custom.write_snapshot(Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn top_mut_of_empty_queue() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        assert!(queue.top_mut().is_none());
+    }
+
+    #[test]
+    fn top_mut_sifts_down_modified_key() {
+        let mut queue: PQueue<u32, String> = PQueue::new(Priority::Asc);
+        let handles: Vec<Handle> = (0..20).map(|n| queue.insert_kv(n * 2, n.to_string())).collect();
+        {
+            let mut top = queue.top_mut().unwrap();
+            assert_eq!(*top.key(), 0);
+            assert_eq!(top.handle(), handles[0]);
+            *top.key_mut() = 25;
+            top.value_mut().unwrap().push('!');
+        }
+        assert_heap(&queue);
+        assert_eq!(queue.top_k(), Some(&2));
+        assert_eq!(queue.remove(handles[0]), Ok((25, Some(String::from("0!")))));
+        let keys: Vec<u32> = queue.map(|(key, _)| key).collect();
+        assert_eq!(keys, (1..20).map(|n| n * 2).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn top_mut_keeps_top_with_higher_priority() {
+        let mut queue: PQueue<i32, ()> = PQueue::new(Priority::Desc);
+        queue.bulk_insert_k(vec![5, 3, 8, 1]);
+        *queue.top_mut().unwrap().key_mut() = 10;
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(4), vec![10, 5, 3, 1]);
+    }
+
+    #[test]
+    fn top_mut_value_only() {
+        let mut queue: PQueue<u8, Vec<u8>> = PQueue::new(Priority::Asc);
+        queue.insert_kv(1, vec![1]);
+        queue.insert_k(2);
+        queue.top_mut().unwrap().value_mut().unwrap().push(2);
+        assert_eq!(queue.top_kv(), Some((&1, Some(&vec![1, 2]))));
+        assert_eq!(queue.pop_k(), Some(1));
+        let top = queue.top_mut().unwrap();
+        assert_eq!(top.value(), None);
+    }
+
+    #[test]
+    fn pop_from_top_mut() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        for key in [4, 1, 3, 2] {
+            queue.insert_kv(key, key.to_string());
+        }
+        let mut top = queue.top_mut().unwrap();
+        *top.key_mut() = 9;
+        assert_eq!(PeekMut::pop(top), (9, Some(String::from("1"))));
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(3), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn push_pop_returns_key_with_higher_priority() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        assert_eq!(queue.push_pop_kv(3, String::from("three")), (3, Some(String::from("three"))));
+        assert!(queue.is_empty());
+        queue.bulk_insert_k(vec![5, 2, 8]);
+        assert_eq!(queue.push_pop_k(1), (1, None));
+        assert_eq!(queue.push_pop_k(2), (2, None));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.bulk_pop_k(3), vec![2, 5, 8]);
+    }
+
+    #[test]
+    fn push_pop_swaps_in_at_the_root() {
+        let mut queue: PQueue<u32, String> = PQueue::new(Priority::Desc);
+        let handles: Vec<Handle> = (0..30).map(|n| queue.insert_kv(n, n.to_string())).collect();
+        assert_eq!(queue.push_pop_kv(15, String::from("new")), (29, Some(String::from("29"))));
+        assert_heap(&queue);
+        assert!(!queue.contains(handles[29]));
+        assert_eq!(queue.len(), 30);
+        let keys: Vec<u32> = queue.bulk_pop_k(30);
+        let mut expected: Vec<u32> = (0..29).rev().collect();
+        expected.insert(13, 15);
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn push_pop_in_stable_queue() {
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).stable();
+        queue.insert_kv(1, "old");
+        assert_eq!(queue.push_pop_kv(1, "new"), (1, Some("old")));
+        assert_eq!(queue.push_pop_kv(1, "newer"), (1, Some("new")));
+        assert_eq!(queue.pop_kv(), Some((1, Some("newer"))));
+    }
+
+    #[test]
+    fn replace_top() {
+        let mut queue: PQueue<i32, String> = PQueue::new(Priority::Asc);
+        assert_eq!(queue.replace_top_kv(4, String::from("four")), None);
+        assert_eq!(queue.len(), 1);
+        queue.bulk_insert_k(vec![7, 2, 9]);
+        assert_eq!(queue.replace_top_k(1), Some((2, None)));
+        assert_eq!(queue.top_k(), Some(&1));
+        assert_eq!(queue.replace_top_k(10), Some((1, None)));
+        assert_heap(&queue);
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.pop_kv(), Some((4, Some(String::from("four")))));
+        assert_eq!(queue.bulk_pop_k(3), vec![7, 9, 10]);
+    }
+
+    #[test]
+    fn key_lookups_without_index() {
+        let mut queue: PQueue<u8, String> = PQueue::new(Priority::Asc);
+        queue.insert_kv(3, String::from("a"));
+        queue.insert_kv(1, String::from("b"));
+        queue.insert_k(3);
+        assert!(!queue.is_indexed());
+        assert!(queue.contains_key(&3));
+        assert!(!queue.contains_key(&2));
+        assert_eq!(queue.count_key(&3), 2);
+        let mut values = queue.get(&3);
+        values.sort();
+        assert_eq!(values, [None, Some(&String::from("a"))]);
+        assert!(queue.remove_key(&3).is_some());
+        assert!(queue.remove_key(&3).is_some());
+        assert_eq!(queue.remove_key(&3), None);
+        assert_heap(&queue);
+        assert_eq!(queue.pop_kv(), Some((1, Some(String::from("b")))));
+    }
+
+    #[test]
+    fn indexed_key_lookups() {
+        let mut queue: PQueue<String, u32> = PQueue::new(Priority::Asc);
+        queue.insert_kv(String::from("b"), 0);
+        let mut queue = queue.indexed();
+        assert!(queue.is_indexed());
+        for n in 1..30 {
+            queue.insert_kv(["a", "b", "c"][n as usize % 3].to_string(), n);
+        }
+        assert_heap(&queue);
+        assert_eq!(queue.count_key(&String::from("b")), 11);
+        assert!(!queue.contains_key(&String::from("d")));
+        let mut values: Vec<u32> = queue.get(&String::from("a")).into_iter().map(|value| *value.unwrap()).collect();
+        values.sort();
+        assert_eq!(values, (1..30).filter(|n| n % 3 == 0).collect::<Vec<u32>>());
+        let (key, value) = queue.remove_key(&String::from("c")).unwrap();
+        assert_eq!(key, "c");
+        assert_eq!(value.unwrap() % 3, 2);
+        assert_eq!(queue.count_key(&String::from("c")), 9);
+        assert_heap(&queue);
+    }
+
+    #[test]
+    fn key_index_follows_every_operation() {
+        let mut queue: PQueue<u32, u32, 3> = PQueue::with_max_len(40, Priority::Desc).indexed();
+        let handles: Vec<Handle> = (0..50).map(|n| queue.insert_kv(n % 10, n)).collect();
+        assert_heap(&queue);
+        assert_eq!(queue.count_key(&0), 0);
+        queue.update_key(handles[49], 20).unwrap();
+        assert_eq!(queue.count_key(&20), 1);
+        assert_heap(&queue);
+        queue.remove(handles[48]).unwrap();
+        queue.pop_kv();
+        assert_eq!(queue.count_key(&20), 0);
+        *queue.top_mut().unwrap().key_mut() = 0;
+        assert_eq!(queue.count_key(&0), 1);
+        assert_heap(&queue);
+        let mut top = queue.top_mut().unwrap();
+        *top.key_mut() = 100;
+        PeekMut::pop(top);
+        assert!(!queue.contains_key(&100));
+        queue.push_pop_k(15);
+        queue.replace_top_kv(6, 1000);
+        assert_eq!(queue.get(&6).into_iter().filter(|value| *value == Some(&1000)).count(), 1);
+        assert_heap(&queue);
+        queue.bulk_insert_kv((0..100).map(|n| (n % 7, n)).collect());
+        assert_heap(&queue);
+        queue.retain(|key, _| key % 2 == 0);
+        assert_heap(&queue);
+        assert!(!queue.contains_key(&5));
+        let mut other = queue.new_like();
+        assert!(other.is_indexed());
+        other.insert_k(2);
+        queue.append(&mut other).unwrap();
+        assert!(!other.contains_key(&2));
+        assert_heap(&queue);
+        drop(queue.drain());
+        assert!(!queue.contains_key(&2));
+        queue.insert_k(2);
+        queue.clear();
+        assert_eq!(queue.count_key(&2), 0);
+    }
+
+    #[test]
+    fn unique_keys_reject() {
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::Reject);
+        assert!(queue.is_indexed());
+        assert_eq!(queue.duplicate_policy(), Some(DuplicatePolicy::Reject));
+        let handle = queue.insert_kv(3, "first");
+        assert_eq!(queue.offer_kv(3, "second"), Offer::Rejected((3, Some("second"))));
+        let rejected = queue.insert_kv(3, "third");
+        assert!(!queue.contains(rejected));
+        assert!(queue.contains(handle));
+        queue.bulk_insert_kv(vec![(1, "one"), (3, "fourth"), (1, "uno")]);
+        assert_eq!(queue.len(), 2);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(2), vec![(1, Some("one")), (3, Some("first"))]);
+    }
+
+    #[test]
+    fn unique_keys_replace() {
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Desc).unique(DuplicatePolicy::Replace);
+        let handle = queue.insert_kv(3, "first");
+        match queue.offer_kv(3, "second") {
+            Offer::Evicted(new_handle, evicted) => {
+                assert_eq!(evicted, (3, Some("first")));
+                assert!(queue.contains(new_handle));
+            }
+            offer => panic!("unexpected offer {:?}", offer),
+        }
+        assert!(!queue.contains(handle));
+        queue.insert_k(5);
+        assert_eq!(queue.push_pop_kv(3, "third"), (3, Some("second")));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.replace_top_kv(3, "fourth"), Some((3, Some("third"))));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.replace_top_kv(5, "five"), Some((5, None)));
+        assert_heap(&queue);
+        assert_eq!(queue.pop_kv(), Some((5, Some("five"))));
+        assert_eq!(queue.pop_kv(), Some((3, Some("fourth"))));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn push_pop_and_replace_top_with_rejected_duplicate() {
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::Reject);
+        queue.insert_kv(1, "top");
+        queue.insert_kv(5, "five");
+        assert_eq!(queue.push_pop_kv(5, "new five"), (5, Some("new five")));
+        assert_eq!(queue.replace_top_kv(5, "other five"), Some((5, Some("other five"))));
+        assert_eq!(queue.push_pop_kv(1, "new top"), (1, Some("new top")));
+        assert_eq!(queue.replace_top_kv(1, "next top"), Some((1, Some("top"))));
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(2), vec![(1, Some("next top")), (5, Some("five"))]);
+    }
+
+    #[test]
+    fn push_pop_and_replace_top_keeping_better_duplicate() {
+        let mut queue = visits_queue();
+        queue.insert_kv(visit(1, 2), "top");
+        queue.insert_kv(visit(5, 20), "five");
+        let pair = |(visit, value): (Visit, Option<&'static str>)| (visit.node, visit.distance, value);
+        assert_eq!(pair(queue.push_pop_kv(visit(5, 30), "worse")), (5, 30, Some("worse")));
+        assert_eq!(pair(queue.push_pop_kv(visit(5, 10), "better")), (5, 20, Some("five")));
+        assert_eq!(pair(queue.replace_top_kv(visit(5, 15), "worse").unwrap()), (5, 15, Some("worse")));
+        assert_eq!(pair(queue.replace_top_kv(visit(5, 1), "best").unwrap()), (5, 10, Some("better")));
+        assert_eq!(queue.len(), 2);
+        assert_heap(&queue);
+        let popped: Vec<_> = queue.map(pair).collect();
+        assert_eq!(popped, [(5, 1, Some("best")), (1, 2, Some("top"))]);
+    }
+
+    // visits are equal when they reach the same node, but the queue orders them by distance
+    #[derive(Debug, Clone, Copy)]
+    struct Visit {
+        node: u32,
+        distance: u32,
+    }
+    impl PartialEq for Visit {
+        fn eq(&self, other: &Self) -> bool {
+            self.node == other.node
+        }
+    }
+    impl Eq for Visit {}
+    impl std::hash::Hash for Visit {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            self.node.hash(state);
+        }
+    }
+    impl PartialOrd for Visit {
+        fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Visit {
+        fn cmp(&self, other: &Self) -> cmp::Ordering {
+            self.node.cmp(&other.node)
+        }
+    }
+
+    fn visit(node: u32, distance: u32) -> Visit {
+        Visit { node, distance }
+    }
+
+    fn visits_queue() -> PQueue<Visit, &'static str> {
+        let queue = PQueue::with_comparator(|a: &Visit, b: &Visit| a.distance.cmp(&b.distance));
+        queue.unique(DuplicatePolicy::KeepBetter)
+    }
+
+    #[test]
+    fn unique_keys_keep_better() {
+        let mut queue = visits_queue();
+        queue.insert_k(visit(1, 10));
+        queue.insert_k(visit(2, 5));
+        assert!(matches!(queue.offer_k(visit(1, 12)), Offer::Rejected(_)));
+        assert!(matches!(queue.offer_k(visit(1, 3)), Offer::Evicted(_, (Visit { distance: 10, .. }, None))));
+        queue.bulk_insert_k(vec![visit(3, 7), visit(2, 8), visit(3, 1)]);
+        assert_heap(&queue);
+        let popped: Vec<(u32, u32)> = queue.map(|(visit, _)| (visit.node, visit.distance)).collect();
+        assert_eq!(popped, [(3, 1), (1, 3), (2, 5)]);
+    }
+
+    #[test]
+    fn unique_keys_of_existing_items() {
+        let mut queue: PQueue<u8, u32> = PQueue::new(Priority::Asc).stable();
+        for n in 0..12 {
+            queue.insert_kv(n as u8 % 4, n);
+        }
+        let mut queue = queue.unique(DuplicatePolicy::Replace);
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(4), vec![(0, Some(8)), (1, Some(9)), (2, Some(10)), (3, Some(11))]);
+    }
+
+    #[test]
+    fn set_key_of_top_in_unique_queue() {
+        let mut queue: PQueue<u8, &str> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::Replace);
+        queue.insert_kv(1, "one");
+        queue.insert_kv(2, "two");
+        assert_eq!(queue.top_mut().unwrap().set_key(2), Err(PQueueError::DuplicateKey));
+        assert_eq!(queue.count_key(&2), 1);
+        assert_eq!(queue.top_mut().unwrap().set_key(1), Ok(1));
+        assert_eq!(queue.top_mut().unwrap().set_key(3), Ok(1));
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_kv(2), vec![(2, Some("two")), (3, Some("one"))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unique keys")]
+    fn key_mut_of_top_in_unique_queue() {
+        let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::Reject);
+        queue.insert_k(1);
+        queue.insert_k(2);
+        *queue.top_mut().unwrap().key_mut() = 2;
+    }
+
+    #[test]
+    fn update_to_duplicate_key() {
+        let mut queue: PQueue<u8, ()> = PQueue::new(Priority::Asc).unique(DuplicatePolicy::Reject);
+        let handle = queue.insert_k(1);
+        queue.insert_k(2);
+        assert_eq!(queue.update_key(handle, 2), Err(PQueueError::DuplicateKey));
+        assert_eq!(queue.update_key(handle, 1), Ok(()));
+        assert_eq!(queue.update_key(handle, 3), Ok(()));
+        let mut other = queue.new_like();
+        other.insert_k(3);
+        other.insert_k(4);
+        queue.append(&mut other).unwrap();
+        assert_heap(&queue);
+        assert_eq!(queue.bulk_pop_k(4), vec![2, 3, 4]);
+    }
 }
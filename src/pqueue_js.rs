@@ -1,16 +1,18 @@
 
-use std::rc::Rc;
 use std::cmp;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 
-use js_sys::{Array, Error, TypeError};
+use js_sys::{Array, Error, Function, Reflect, TypeError, JSON};
 use wasm_bindgen::prelude::*;
 
-use crate::pqueue::{PQueue, Priority};
+use crate::pqueue::{DuplicatePolicy, Offer, PQueue, Priority};
+use crate::snapshot::{self, SnapshotCodec};
 
 
 #[derive(Copy, Clone, Debug)]
-struct NumberJs (f64);
+pub(crate) struct NumberJs (pub(crate) f64);
 
 impl NumberJs {
     pub fn new(f: f64) -> NumberJs {
@@ -43,12 +45,66 @@ impl PartialEq for NumberJs {
 
 impl Eq for NumberJs {}
 
+// Equal numbers must have the same hash, adding 0.0 turns -0.0 into 0.0.
+impl Hash for NumberJs {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 + 0.0).to_bits().hash(state);
+    }
+}
+
 impl fmt::Display for NumberJs {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(fmt, "{}", self.0)
     }
 }
 
+impl SnapshotCodec for NumberJs {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        f64::decode(reader).map(NumberJs)
+    }
+}
+
+// Values are stored as JSON, so only the values supported by `JSON.stringify` can be written in a snapshot.
+impl SnapshotCodec for JsValue {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let json = JSON::stringify(self)
+            .ok()
+            .and_then(|json| json.as_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The values should be convertible to JSON"))?;
+        json.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        JSON::parse(&String::decode(reader)?).map_err(|_| snapshot::invalid_data("Invalid JSON value"))
+    }
+}
+
+fn get_option(options: &JsValue, name: &str) -> JsValue {
+    if options.is_undefined() {
+        JsValue::UNDEFINED
+    } else {
+        Reflect::get(options, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED)
+    }
+}
+
+pub(crate) fn pair_to_array(key: &NumberJs, value: Option<&JsValue>) -> Array {
+    match value {
+        Some(value) => Array::of2(&JsValue::from_f64(key.0), value),
+        None => Array::of1(&JsValue::from_f64(key.0)),
+    }
+}
+
+fn dropped_pair(offer: Offer<NumberJs, JsValue>) -> Option<Array> {
+    match offer {
+        Offer::Inserted(_) => None,
+        Offer::Evicted(_, (key, value)) | Offer::Rejected((key, value)) => Some(pair_to_array(&key, value.as_ref())),
+    }
+}
+
 #[wasm_bindgen( js_name = PQueue )]
 pub struct PQueueJs {
     queue: PQueue<NumberJs, JsValue>,
@@ -62,15 +118,61 @@ impl PQueueJs {
     /// If the `order` is "asc" then it's a ascending priority queue.
     /// If the `order` is "desc" then it's a descending priority queue.
     /// Otherwise, it throws a exception.
-    pub fn new(order: String) -> Result<PQueueJs, TypeError> {
-        let queue;
+    ///
+    /// The optional `options` object accepts the following fields:
+    /// - `stable`: if `true`, keys with the same priority are popped in the order they were inserted.
+    /// - `maxLen`: the maximum number of keys retained, when the queue is full the key with lowest priority is dropped.
+    /// - `indexed`: if `true`, the keys are indexed to speed up `has`, `count` and `deleteKey`.
+    /// - `unique`: if it's "reject", "replace" or "keepBetter", each key is at most once in the queue.
+    ///   Inserting a key already in the queue rejects the inserted key, replaces the key in the queue,
+    ///   or keeps the one with higher priority, returning the dropped pair key/value.
+    pub fn new(order: String, options: JsValue) -> Result<PQueueJs, TypeError> {
+        let priority;
         if order == String::from("asc") {
-            queue = PQueue::new(Priority::Asc);
+            priority = Priority::Asc;
         } else if order == String::from("desc") {
-            queue = PQueue::new(Priority::Desc);
+            priority = Priority::Desc;
         } else {
             return Err(TypeError::new("The order parameter should be asc or desc"));
         }
+        if !options.is_undefined() && !options.is_object() {
+            return Err(TypeError::new("The options parameter should be an object"));
+        }
+        let max_len = get_option(&options, "maxLen");
+        let mut queue = if max_len.is_undefined() {
+            PQueue::new(priority)
+        } else {
+            match max_len.as_f64() {
+                Some(n) if n >= 0.0 && n.fract() == 0.0 => PQueue::with_max_len(n as usize, priority),
+                _ => return Err(TypeError::new("The maxLen option should be a non-negative integer")),
+            }
+        };
+        let stable = get_option(&options, "stable");
+        if !stable.is_undefined() {
+            match stable.as_bool() {
+                Some(true) => queue = queue.stable(),
+                Some(false) => (),
+                None => return Err(TypeError::new("The stable option should be a boolean")),
+            }
+        }
+        let indexed = get_option(&options, "indexed");
+        if !indexed.is_undefined() {
+            match indexed.as_bool() {
+                Some(true) => queue = queue.indexed(),
+                Some(false) => (),
+                None => return Err(TypeError::new("The indexed option should be a boolean")),
+            }
+        }
+        let unique = get_option(&options, "unique");
+        if !unique.is_undefined() {
+            let policy = match unique.as_string().as_deref() {
+                Some("reject") => DuplicatePolicy::Reject,
+                Some("replace") => DuplicatePolicy::Replace,
+                Some("keepBetter") => DuplicatePolicy::KeepBetter,
+                _ => return Err(TypeError::new("The unique option should be reject, replace or keepBetter")),
+            };
+            queue = queue.unique(policy);
+        }
         let queue_js = PQueueJs{ queue };
         Ok(queue_js)
     }
@@ -85,12 +187,12 @@ impl PQueueJs {
     /// Insert a key in the priority queue.
     ///
     /// The method assumes that there is no data associated with the inserted key.
-    pub fn insert_k(&mut self, key_op: Option<f64>) -> Result<(), TypeError> {
+    /// If the queue was built with the `maxLen` option and it's full, it returns the dropped pair key/value,
+    /// which is the inserted key if it doesn't have higher priority than the keys in the queue.
+    /// With the `unique` option, it also returns the dropped pair if the key was already in the queue.
+    pub fn insert_k(&mut self, key_op: Option<f64>) -> Result<Option<Array>, TypeError> {
         match key_op {
-            Some(key) => {
-                self.queue.insert_k(NumberJs::new(key));
-                Ok(())
-            }
+            Some(key) => Ok(dropped_pair(self.queue.offer_k(NumberJs::new(key)))),
             None => Err(TypeError::new("insertK expect a number")),
         }
     }
@@ -99,12 +201,10 @@ impl PQueueJs {
     /// Insert a key in the priority queue. `insertKV` stands for `insert key and value`.
     ///
     /// The `value` should be any data associated with the inserted key.
-    pub fn insert_kv(&mut self, key_op: Option<f64>, value: JsValue) -> Result<(), TypeError> {
+    /// Like `insertK`, it returns the dropped pair key/value if the queue is full.
+    pub fn insert_kv(&mut self, key_op: Option<f64>, value: JsValue) -> Result<Option<Array>, TypeError> {
         match key_op {
-            Some(key) => {
-                self.queue.insert_kv(NumberJs::new(key), value);
-                Ok(())
-            }
+            Some(key) => Ok(dropped_pair(self.queue.offer_kv(NumberJs::new(key), value))),
             None => Err(TypeError::new("insertK expect a number")),
         }
     }
@@ -113,6 +213,7 @@ impl PQueueJs {
     /// Insert a array of keys in the priority queue. This method is roughly equivalent for calling `insert_k` for each element of the array `keys`, but it's more faster. 
     ///
     /// This method is more faster than calling `insert_k` for each key, because using this method just only one call to webassembly happens.
+    /// When the array is large compared to the queue, the heap is rebuilt at once in linear time instead of inserting each key.
     pub fn bulk_insert_k(&mut self, keys: Vec<JsValue>) -> Result<(), TypeError> {
         let mut vec = Vec::new();
         for key_js in keys {
@@ -131,6 +232,7 @@ impl PQueueJs {
     /// This method is roughly equivalent for calling `insert_kv` for each element of the two array `keys` and `values`, but it's more faster. 
     ///
     /// Both array must have the same length, otherwise, an error is thrown.
+    /// Like `bulkInsertK`, the heap is rebuilt at once in linear time when the arrays are large compared to the queue.
     pub fn bulk_insert_kv(&mut self, keys: Vec<JsValue>, values: Vec<JsValue>) -> Result<(), TypeError> {
         if keys.len() != values.len() {
             return Err(TypeError::new("The arrays should have the same length"));
@@ -243,11 +345,151 @@ impl PQueueJs {
         let value = self.queue.top_kv();
         value
             .map(|pair| match pair.1 {
-                Some(rc) => Array::of2(&JsValue::from_f64(pair.0.0), &rc),
+                Some(value) => Array::of2(&JsValue::from_f64(pair.0.0), value),
                 None => Array::of1(&JsValue::from_f64(pair.0.0)),
             })
             .ok_or(Error::new("Cannot call top from empty queue"))
     }
+
+    #[wasm_bindgen( js_name = pushPop )]
+    /// Insert a key and pop the pair key/value with higher priority, faster than calling `insertKV` and `popKV`.
+    ///
+    /// If the key has higher priority than the keys in the queue, it's returned right away.
+    /// If the `value` is `undefined`, the key is inserted without associated data.
+    /// The returned array is like the returned array of `popKV`.
+    /// In a queue with unique keys, if the key is already in the queue, the top isn't popped: the pair dropped by
+    /// the `unique` policy is returned instead.
+    pub fn push_pop(&mut self, key_op: Option<f64>, value: JsValue) -> Result<Array, TypeError> {
+        let key = NumberJs::new(key_op.ok_or_else(|| TypeError::new("pushPop expect a number"))?);
+        let (key, value) = if value.is_undefined() {
+            self.queue.push_pop_k(key)
+        } else {
+            self.queue.push_pop_kv(key, value)
+        };
+        Ok(pair_to_array(&key, value.as_ref()))
+    }
+
+    #[wasm_bindgen( js_name = replaceTop )]
+    /// Pop the pair key/value with higher priority and insert a key, faster than calling `popKV` and `insertKV`.
+    ///
+    /// Unlike `pushPop`, the pair with higher priority is popped even if the inserted key has higher priority.
+    /// If the queue is empty, the key is inserted and it returns `undefined`.
+    /// Like in `pushPop`, if another key in a queue with unique keys is equal to the key, the top isn't popped.
+    pub fn replace_top(&mut self, key_op: Option<f64>, value: JsValue) -> Result<Option<Array>, TypeError> {
+        let key = NumberJs::new(key_op.ok_or_else(|| TypeError::new("replaceTop expect a number"))?);
+        let popped = if value.is_undefined() {
+            self.queue.replace_top_k(key)
+        } else {
+            self.queue.replace_top_kv(key, value)
+        };
+        Ok(popped.map(|(key, value)| pair_to_array(&key, value.as_ref())))
+    }
+
+    #[wasm_bindgen( js_name = toArray )]
+    /// Return an array with all the pairs key/value of the queue without removing them.
+    ///
+    /// The pairs are in the order they are stored in the heap, so only the first one is guaranteed to have the highest priority.
+    /// Each element of the returned array is like the returned element of `popKV`.
+    pub fn to_array(&self) -> Vec<Array> {
+        self.queue
+            .iter()
+            .map(|(key, value)| pair_to_array(key, value))
+            .collect()
+    }
+
+    #[wasm_bindgen( js_name = toSortedArray )]
+    /// Return an array with all the pairs key/value of the queue in the order they would be popped, without removing them.
+    ///
+    /// Each element of the returned array is like the returned element of `popKV`.
+    pub fn to_sorted_array(&self) -> Vec<Array> {
+        self.queue
+            .to_sorted_vec()
+            .iter()
+            .map(|(key, value)| pair_to_array(key, value.as_ref()))
+            .collect()
+    }
+
+    /// Keep only the keys for which `callback(key, value)` returns a truthy value, removing the others.
+    ///
+    /// The `value` is `undefined` for keys inserted without associated data.
+    /// If the callback throws an exception, the exception is propagated and the remaining keys are kept.
+    pub fn retain(&mut self, callback: &Function) -> Result<(), JsValue> {
+        let mut exception = None;
+        self.queue.retain(|key, value| {
+            if exception.is_some() {
+                return true;
+            }
+            let value = value.cloned().unwrap_or(JsValue::UNDEFINED);
+            match callback.call2(&JsValue::NULL, &JsValue::from_f64(key.0), &value) {
+                Ok(keep) => keep.is_truthy(),
+                Err(err) => {
+                    exception = Some(err);
+                    true
+                }
+            }
+        });
+        match exception {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the key is in the queue.
+    pub fn has(&self, key: f64) -> bool {
+        self.queue.contains_key(&NumberJs::new(key))
+    }
+
+    /// The number of times the key is in the queue.
+    pub fn count(&self, key: f64) -> usize {
+        self.queue.count_key(&NumberJs::new(key))
+    }
+
+    #[wasm_bindgen( js_name = deleteKey )]
+    /// Remove one occurrence of the key from the queue, returning the removed pair key/value.
+    ///
+    /// It returns `undefined` if the key isn't in the queue. The returned array is like the returned array of `popKV`.
+    pub fn delete_key(&mut self, key: f64) -> Option<Array> {
+        self.queue
+            .remove_key(&NumberJs::new(key))
+            .map(|(key, value)| pair_to_array(&key, value.as_ref()))
+    }
+
+    /// Remove all the keys of the queue.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    #[wasm_bindgen( js_name = toBytes )]
+    /// Return a compact binary snapshot of the queue, which `PQueue.fromBytes` restores.
+    ///
+    /// The snapshot keeps the order and the options of the queue. The values are stored as JSON,
+    /// so an error is thrown if a value can't be converted with `JSON.stringify`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.queue
+            .write_snapshot(&mut bytes)
+            .map_err(|err| Error::new(&err.to_string()))?;
+        Ok(bytes)
+    }
+
+    #[wasm_bindgen( js_name = fromBytes )]
+    /// Construct a queue from a `Uint8Array` returned by `toBytes`.
+    ///
+    /// If the bytes aren't a valid snapshot, or they were corrupted, an error is thrown.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PQueueJs, Error> {
+        PQueue::read_indexed_snapshot(bytes)
+            .map(|queue| PQueueJs { queue })
+            .map_err(|err| Error::new(&format!("Invalid snapshot: {}", err)))
+    }
+
+    /// Move all the keys and values of `other` into this queue, leaving `other` empty.
+    ///
+    /// Both queues must have the same order, otherwise, an error is thrown.
+    pub fn merge(&mut self, other: &mut PQueueJs) -> Result<(), Error> {
+        self.queue
+            .append(&mut other.queue)
+            .map_err(|_| Error::new("Cannot merge queues with different orders"))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,334 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::pqueue::{DuplicatePolicy, Priority};
+
+
+pub(crate) const MAGIC: [u8; 4] = *b"EPQS";
+pub(crate) const VERSION: u8 = 2;
+
+/// Encodes keys and values in the binary snapshots written by `PQueue::write_snapshot`.
+///
+/// Numbers are written in little-endian, strings and vectors are prefixed by their length.
+pub trait SnapshotCodec: Sized {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+macro_rules! number_codec {
+    ($($number:ty),*) => {
+        $(
+            impl SnapshotCodec for $number {
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$number>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$number>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+number_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// Written as a `u64`, so snapshots can be read in platforms with a different pointer width.
+impl SnapshotCodec for usize {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u64).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        usize::try_from(u64::decode(reader)?).map_err(|_| invalid_data("the number doesn't fit in usize"))
+    }
+}
+
+impl SnapshotCodec for bool {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid boolean")),
+        }
+    }
+}
+
+impl SnapshotCodec for char {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u32).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        char::from_u32(u32::decode(reader)?).ok_or_else(|| invalid_data("invalid char"))
+    }
+}
+
+impl SnapshotCodec for () {
+    fn encode<W: Write>(&self, _writer: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn decode<R: Read>(_reader: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl SnapshotCodec for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = usize::decode(reader)?;
+        let mut bytes = Vec::new();
+        reader.take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(bytes).map_err(|_| invalid_data("invalid UTF-8 string"))
+    }
+}
+
+impl<T: SnapshotCodec> SnapshotCodec for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().encode(writer)?;
+        self.iter().try_for_each(|element| element.encode(writer))
+    }
+
+    // The length isn't trusted to reserve memory, a corrupted length fails when the data ends.
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = usize::decode(reader)?;
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+impl<T: SnapshotCodec> SnapshotCodec for Option<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.is_some().encode(writer)?;
+        match self {
+            Some(value) => value.encode(writer),
+            None => Ok(()),
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        if bool::decode(reader)? {
+            T::decode(reader).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<A: SnapshotCodec, B: SnapshotCodec> SnapshotCodec for (A, B) {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.encode(writer)?;
+        self.1.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+impl SnapshotCodec for Duration {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.as_secs().encode(writer)?;
+        self.subsec_nanos().encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let secs = u64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+        if nanos >= 1_000_000_000 {
+            return Err(invalid_data("invalid duration"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+impl SnapshotCodec for Priority {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Priority::Asc => 0u8.encode(writer),
+            Priority::Desc => 1u8.encode(writer),
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(Priority::Asc),
+            1 => Ok(Priority::Desc),
+            _ => Err(invalid_data("invalid priority")),
+        }
+    }
+}
+
+impl SnapshotCodec for DuplicatePolicy {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            DuplicatePolicy::Reject => 0u8.encode(writer),
+            DuplicatePolicy::Replace => 1u8.encode(writer),
+            DuplicatePolicy::KeepBetter => 2u8.encode(writer),
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(DuplicatePolicy::Reject),
+            1 => Ok(DuplicatePolicy::Replace),
+            2 => Ok(DuplicatePolicy::KeepBetter),
+            _ => Err(invalid_data("invalid duplicate policy")),
+        }
+    }
+}
+
+// 32-bit FNV-1a, it detects corrupted snapshots but it doesn't protect against forged ones.
+struct Checksum(u32);
+
+impl Checksum {
+    fn new() -> Checksum {
+        Checksum(0x811c9dc5)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u32).wrapping_mul(0x01000193);
+        }
+    }
+}
+
+/// Computes the checksum of the written bytes, `finish` appends it.
+pub(crate) struct ChecksumWriter<W: Write> {
+    writer: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(writer: W) -> ChecksumWriter<W> {
+        ChecksumWriter { writer, checksum: Checksum::new() }
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(&self.checksum.0.to_le_bytes())?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.checksum.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Computes the checksum of the read bytes, `verify` compares it with the checksum at the end of the snapshot.
+pub(crate) struct ChecksumReader<R: Read> {
+    reader: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) fn new(reader: R) -> ChecksumReader<R> {
+        ChecksumReader { reader, checksum: Checksum::new() }
+    }
+
+    pub(crate) fn verify(mut self) -> io::Result<()> {
+        let mut bytes = [0; 4];
+        self.reader.read_exact(&mut bytes)?;
+        if u32::from_le_bytes(bytes) == self.checksum.0 {
+            Ok(())
+        } else {
+            Err(invalid_data("the checksum of the snapshot doesn't match"))
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.checksum.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: SnapshotCodec>(value: &T) -> T {
+        let mut bytes = Vec::new();
+        value.encode(&mut bytes).unwrap();
+        let mut reader = bytes.as_slice();
+        let decoded = T::decode(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        assert_eq!(round_trip(&-7i32), -7);
+        assert_eq!(round_trip(&u64::MAX), u64::MAX);
+        assert_eq!(round_trip(&1.5f64), 1.5);
+        assert_eq!(round_trip(&12usize), 12);
+        assert!(round_trip(&true));
+        assert_eq!(round_trip(&'λ'), 'λ');
+        assert_eq!(round_trip(&String::from("snapshot")), "snapshot");
+        assert_eq!(round_trip(&vec![1u8, 2, 3]), [1, 2, 3]);
+        assert_eq!(round_trip(&Some(3u16)), Some(3));
+        assert_eq!(round_trip(&None::<u16>), None);
+        assert_eq!(round_trip(&(2u8, String::from("two"))), (2, String::from("two")));
+        assert_eq!(round_trip(&Priority::Desc), Priority::Desc);
+    }
+
+    #[test]
+    fn numbers_are_little_endian() {
+        let mut bytes = Vec::new();
+        0x0102u16.encode(&mut bytes).unwrap();
+        assert_eq!(bytes, [2, 1]);
+    }
+
+    #[test]
+    fn invalid_encodings() {
+        assert!(bool::decode(&mut [2u8].as_slice()).is_err());
+        assert!(Priority::decode(&mut [5u8].as_slice()).is_err());
+        assert!(String::decode(&mut [2u8, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff].as_slice()).is_err());
+        assert!(String::decode(&mut [9u8, 0, 0, 0, 0, 0, 0, 0, b'a'].as_slice()).is_err());
+        assert!(Vec::<u32>::decode(&mut [0xffu8; 8].as_slice()).is_err());
+    }
+
+    #[test]
+    fn checksum_detects_changes() {
+        let mut bytes = Vec::new();
+        let mut writer = ChecksumWriter::new(&mut bytes);
+        writer.write_all(b"heap").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(bytes.len(), 8);
+
+        let mut reader = ChecksumReader::new(bytes.as_slice());
+        reader.read_exact(&mut [0; 4]).unwrap();
+        assert!(reader.verify().is_ok());
+
+        bytes[1] ^= 1;
+        let mut reader = ChecksumReader::new(bytes.as_slice());
+        reader.read_exact(&mut [0; 4]).unwrap();
+        assert_eq!(reader.verify().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,230 @@
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::pqueue::{PQueue, Priority};
+
+
+/// A priority queue that can be shared between threads, like in an `Arc<SyncPQueue<K, V>>`.
+///
+/// It uses the same heap as `PQueue` behind a lock. The values are stored in an `Arc`,
+/// so `peek` shares the value with the queue instead of cloning it.
+pub struct SyncPQueue<K: cmp::Ord, V> {
+    queue: Mutex<PQueue<K, Arc<V>>>,
+    // Notified every time an item is pushed, so the threads waiting in `pop_wait` wake up.
+    available: Condvar,
+}
+
+impl<K: cmp::Ord, V> SyncPQueue<K, V> {
+    pub fn new(priority: Priority) -> SyncPQueue<K, V> {
+        SyncPQueue::from_queue(PQueue::new(priority))
+    }
+
+    /// Construct a queue ordered by `compare`, see `PQueue::with_comparator`.
+    pub fn with_comparator<F>(compare: F) -> SyncPQueue<K, V>
+    where
+        F: Fn(&K, &K) -> cmp::Ordering + Send + Sync + 'static
+    {
+        SyncPQueue::from_queue(PQueue::with_comparator(compare))
+    }
+
+    fn from_queue(queue: PQueue<K, Arc<V>>) -> SyncPQueue<K, V> {
+        SyncPQueue {
+            queue: Mutex::new(queue),
+            available: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PQueue<K, Arc<V>>> {
+        self.queue.lock().expect("a thread panicked while holding the queue lock")
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn push(&self, key: K, value: V) {
+        self.lock().insert_kv(key, Arc::new(value));
+        self.available.notify_one();
+    }
+
+    /// Pop the item with higher priority, returning `None` immediately if the queue is empty.
+    pub fn try_pop(&self) -> Option<(K, Arc<V>)> {
+        Self::pop_from(&mut self.lock())
+    }
+
+    /// Pop the item with higher priority, blocking the thread until an item is pushed if the queue is empty.
+    pub fn pop_wait(&self) -> (K, Arc<V>) {
+        let mut queue = self.lock();
+        loop {
+            if let Some(pair) = Self::pop_from(&mut queue) {
+                return pair;
+            }
+            queue = self.available.wait(queue).expect("a thread panicked while holding the queue lock");
+        }
+    }
+
+    /// Like `pop_wait`, but gives up returning `None` if no item is available after `timeout`.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<(K, Arc<V>)> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.lock();
+        loop {
+            if let Some(pair) = Self::pop_from(&mut queue) {
+                return Some(pair);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            queue = self.available
+                .wait_timeout(queue, remaining)
+                .expect("a thread panicked while holding the queue lock")
+                .0;
+        }
+    }
+
+    /// Return the item with higher priority without removing it, the value is shared with the queue.
+    pub fn peek(&self) -> Option<(K, Arc<V>)>
+    where
+        K: Clone
+    {
+        self.lock()
+            .top_kv()
+            .map(|(key, value)| (key.clone(), Self::expect_value(value).clone()))
+    }
+
+    fn pop_from(queue: &mut PQueue<K, Arc<V>>) -> Option<(K, Arc<V>)> {
+        queue.pop_kv().map(|(key, value)| (key, Self::expect_value(value)))
+    }
+
+    // Every item is pushed with a value.
+    fn expect_value<T>(value: Option<T>) -> T {
+        value.expect("items of SyncPQueue always have a value")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn shared_between_threads() {
+        assert_send_sync::<SyncPQueue<u32, String>>();
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let queue: SyncPQueue<u32, String> = SyncPQueue::new(Priority::Asc);
+        assert!(queue.is_empty());
+        assert_eq!(queue.try_pop(), None);
+        queue.push(3, String::from("three"));
+        queue.push(1, String::from("one"));
+        queue.push(2, String::from("two"));
+        assert_eq!(queue.len(), 3);
+        let (key, value) = queue.peek().unwrap();
+        assert_eq!(key, 1);
+        assert_eq!(Arc::strong_count(&value), 2);
+        assert_eq!(queue.try_pop(), Some((1, Arc::new(String::from("one")))));
+        assert_eq!(queue.pop_wait(), (2, Arc::new(String::from("two"))));
+        assert_eq!(queue.pop_timeout(Duration::from_millis(1)), Some((3, Arc::new(String::from("three")))));
+        assert_eq!(queue.pop_timeout(Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn custom_comparator() {
+        let queue: SyncPQueue<i32, ()> = SyncPQueue::with_comparator(|a: &i32, b: &i32| a.abs().cmp(&b.abs()));
+        queue.push(-5, ());
+        queue.push(2, ());
+        queue.push(-1, ());
+        assert_eq!(queue.try_pop().map(|(key, _)| key), Some(-1));
+        assert_eq!(queue.try_pop().map(|(key, _)| key), Some(2));
+    }
+
+    #[test]
+    fn pop_wait_wakes_up_on_push() {
+        let queue: Arc<SyncPQueue<u32, String>> = Arc::new(SyncPQueue::new(Priority::Desc));
+        let consumer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.pop_wait())
+        };
+        thread::sleep(Duration::from_millis(20));
+        queue.push(7, String::from("seven"));
+        assert_eq!(consumer.join().unwrap(), (7, Arc::new(String::from("seven"))));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn stress_producers_and_consumers() {
+        let producers = 4;
+        let consumers = 4;
+        let per_producer = 2_000;
+        let queue: Arc<SyncPQueue<u32, u32>> = Arc::new(SyncPQueue::new(Priority::Asc));
+        let consumer_handles: Vec<_> = (0..consumers)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    for _ in 0..per_producer {
+                        let (key, value) = queue.pop_wait();
+                        assert_eq!(key, *value);
+                        popped.push(key);
+                    }
+                    popped
+                })
+            })
+            .collect();
+        let producer_handles: Vec<_> = (0..producers)
+            .map(|producer| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    for n in 0..per_producer {
+                        let key = producer * per_producer + n;
+                        queue.push(key, key);
+                    }
+                })
+            })
+            .collect();
+        for handle in producer_handles {
+            handle.join().unwrap();
+        }
+        let mut popped = HashSet::new();
+        for handle in consumer_handles {
+            for key in handle.join().unwrap() {
+                assert!(popped.insert(key), "the key {} was popped twice", key);
+            }
+        }
+        assert_eq!(popped.len(), (producers * per_producer) as usize);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn stress_pops_in_priority_order() {
+        let queue: Arc<SyncPQueue<u32, ()>> = Arc::new(SyncPQueue::new(Priority::Desc));
+        let producer_handles: Vec<_> = (0..4)
+            .map(|producer| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || {
+                    for n in 0..1_000 {
+                        queue.push(n * 4 + producer, ());
+                    }
+                })
+            })
+            .collect();
+        for handle in producer_handles {
+            handle.join().unwrap();
+        }
+        let mut keys = Vec::new();
+        while let Some((key, _)) = queue.try_pop() {
+            keys.push(key);
+        }
+        assert_eq!(keys, (0..4_000).rev().collect::<Vec<u32>>());
+    }
+}